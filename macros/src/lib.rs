@@ -2,13 +2,30 @@ use proc_macro::TokenStream;
 
 mod map;
 mod memo;
+mod query;
+mod reactive;
 
 #[proc_macro]
 pub fn map(input: TokenStream) -> TokenStream {
     map::map(input)
 }
 
+#[proc_macro]
+pub fn try_map(input: TokenStream) -> TokenStream {
+    map::try_map(input)
+}
+
 #[proc_macro]
 pub fn memo(input: TokenStream) -> TokenStream {
     memo::memo(input)
 }
+
+#[proc_macro_attribute]
+pub fn query(attr: TokenStream, item: TokenStream) -> TokenStream {
+    query::query(attr, item)
+}
+
+#[proc_macro_derive(Reactive)]
+pub fn derive_reactive(input: TokenStream) -> TokenStream {
+    reactive::derive_reactive(input)
+}
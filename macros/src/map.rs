@@ -54,6 +54,12 @@ pub fn map(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     proc_macro::TokenStream::from(output)
 }
 
+pub fn try_map(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input);
+    let output: proc_macro2::TokenStream = { try_map_int(input) };
+    proc_macro::TokenStream::from(output)
+}
+
 pub fn map_int(input: TokenStream) -> TokenStream {
     let map: Map = parse2(input).unwrap();
     let identifiers: Vec<_> = map.args.iter().map(|a| &a.ident).collect();
@@ -74,6 +80,38 @@ pub fn map_int(input: TokenStream) -> TokenStream {
                 #(let #identifiers = #getters;)*
                 #body
             })
+            .named(::core::concat!(::core::file!(), ":", ::core::line!()))
+        }
+    }
+}
+
+/// Like `map_int`, but the arguments are retrieved with `try_get_ref()` / `try_get()` and errors
+/// are propagated. The body must return a `Result`.
+pub fn try_map_int(input: TokenStream) -> TokenStream {
+    let map: Map = parse2(input).unwrap();
+    let identifiers: Vec<_> = map.args.iter().map(|a| &a.ident).collect();
+    let first = &map.args.first().unwrap().ident;
+    let getters = map.args.iter().map(|a| {
+        let ident = &a.ident;
+        match a.ty {
+            // The `Ref` must be kept alive while the body is evaluated.
+            ArgType::Reference => quote! {
+                let #ident = #ident.try_get_ref()?;
+                let #ident = &*#ident;
+            },
+            ArgType::Value => quote! { let #ident = #ident.try_get()?; },
+        }
+    });
+    let body = &map.body;
+
+    quote! {
+        {
+            #(let #identifiers = #identifiers.clone();)*
+            #first.runtime().try_computed(move || {
+                #(#getters)*
+                #body
+            })
+            .named(::core::concat!(::core::file!(), ":", ::core::line!()))
         }
     }
 }
@@ -53,6 +53,7 @@ pub fn map_int(input: TokenStream) -> TokenStream {
                 move |(#(#identifiers,)*)| {
                 #body
             })
+            .named(::core::concat!(::core::file!(), ":", ::core::line!()))
         }
     }
 }
@@ -0,0 +1,72 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse2, Error, FnArg, ItemFn, Result};
+
+pub fn query(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let output = query_int(attr.into(), item.into()).unwrap_or_else(Error::into_compile_error);
+    proc_macro::TokenStream::from(output)
+}
+
+/// Turns `fn f(rt: &Runtime, args..) -> V` into a function that evaluates its body with
+/// `Runtime::query()`, using the arguments as the key.
+pub fn query_int(attr: TokenStream, item: TokenStream) -> Result<TokenStream> {
+    if !attr.is_empty() {
+        return Err(Error::new_spanned(attr, "#[query] does not take arguments"));
+    }
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = parse2(item)?;
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &sig.generics,
+            "#[query] functions can't be generic",
+        ));
+    }
+    if let Some(asyncness) = &sig.asyncness {
+        return Err(Error::new_spanned(
+            asyncness,
+            "#[query] functions can't be async",
+        ));
+    }
+
+    let mut inputs = sig.inputs.iter().map(|input| match input {
+        FnArg::Typed(arg) => Ok(arg),
+        FnArg::Receiver(receiver) => Err(Error::new_spanned(
+            receiver,
+            "#[query] functions can't take `self`",
+        )),
+    });
+    let Some(runtime) = inputs.next() else {
+        return Err(Error::new_spanned(
+            &sig,
+            "#[query] functions must take a `&Runtime` as their first argument",
+        ));
+    };
+    let runtime = runtime?;
+    let args = inputs.collect::<Result<Vec<_>>>()?;
+
+    let ident = &sig.ident;
+    let output = &sig.output;
+    let runtime_pat = &runtime.pat;
+    let runtime_ty = &runtime.ty;
+    let patterns: Vec<_> = args.iter().map(|arg| &arg.pat).collect();
+    let types: Vec<_> = args.iter().map(|arg| &arg.ty).collect();
+    let names: Vec<_> = (0..args.len()).map(|i| format_ident!("__arg{i}")).collect();
+
+    Ok(quote! {
+        #(#attrs)*
+        #vis fn #ident(__runtime: #runtime_ty, #(#names: #types),*) #output {
+            // Identifies the query in the runtime.
+            struct __Query;
+            fn __compute(#runtime_pat: #runtime_ty, (#(#patterns,)*): (#(#types,)*)) #output
+            #block
+            __runtime.query::<__Query, _, _>((#(#names,)*), __compute)
+        }
+    })
+}
@@ -0,0 +1,86 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse2, Data, DeriveInput, Error, Fields, Result};
+
+pub fn derive_reactive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let output = derive_reactive_int(input.into()).unwrap_or_else(Error::into_compile_error);
+    proc_macro::TokenStream::from(output)
+}
+
+/// Generates `Reactive{Name}`, a struct that holds one var for each field of the input struct.
+pub fn derive_reactive_int(input: TokenStream) -> Result<TokenStream> {
+    let input: DeriveInput = parse2(input)?;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) if !fields.named.is_empty() => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "Reactive can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "Reactive can only be derived for structs",
+            ))
+        }
+    };
+
+    let vis = &input.vis;
+    let ident = &input.ident;
+    let reactive = format_ident!("Reactive{}", ident);
+    let generics = &input.generics;
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let names: Vec<_> = fields.iter().map(|f| f.ident.as_ref().unwrap()).collect();
+    let types: Vec<_> = fields.iter().map(|f| &f.ty).collect();
+    let visibilities = fields.iter().map(|f| &f.vis);
+    let first = names[0];
+    // Bindings that don't collide with the names of the fields.
+    let bindings: Vec<_> = (0..names.len())
+        .map(|i| format_ident!("__field{i}"))
+        .collect();
+
+    let struct_doc = format!("A `{ident}` with one var per field.");
+    // `Value` requires its contents to be `'static`.
+    let predicates = where_clause.into_iter().flat_map(|w| &w.predicates);
+    let where_clause = quote! { where #(#predicates,)* #(#types: 'static,)* };
+
+    Ok(quote! {
+        #[doc = #struct_doc]
+        #[derive(Clone)]
+        #vis struct #reactive #generics #where_clause {
+            #(#visibilities #names: ::granularity::Value<#types>,)*
+        }
+
+        impl #impl_generics #reactive #ty_generics #where_clause {
+            /// Creates the vars in the runtime and initializes them with the fields of the value.
+            #vis fn new(__runtime: &::granularity::Runtime, __value: #ident #ty_generics) -> Self {
+                let #ident { #(#names: #bindings,)* } = __value;
+                Self {
+                    #(#names: __runtime.var(#bindings),)*
+                }
+            }
+
+            /// Returns a computed value that combines the current values of all fields.
+            #vis fn snapshot(&self) -> ::granularity::Value<#ident #ty_generics>
+            where
+                #(#types: ::core::clone::Clone,)*
+            {
+                #(let #bindings = self.#names.clone();)*
+                self.#first.runtime().computed(move || #ident {
+                    #(#names: #bindings.get(),)*
+                })
+            }
+
+            /// Sets all fields in a batch, so that readers are invalidated only once.
+            #vis fn set_all(&mut self, __value: #ident #ty_generics) {
+                let #ident { #(#names: #bindings,)* } = __value;
+                self.#first.runtime().batch(|| {
+                    #(self.#names.set(#bindings);)*
+                });
+            }
+        }
+    })
+}
@@ -0,0 +1,150 @@
+use crate::runtime::{NodeId, NodeLabel};
+use std::{fmt, panic::Location};
+
+/// Describes why a value was invalidated.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct InvalidationCause {
+    location: &'static Location<'static>,
+    path: Vec<NodeLabel>,
+}
+
+impl InvalidationCause {
+    pub(crate) fn new(location: &'static Location<'static>) -> Self {
+        InvalidationCause {
+            location,
+            path: Vec::new(),
+        }
+    }
+
+    /// The value that was changed.
+    pub fn source(&self) -> NodeId {
+        self.path[0].id
+    }
+
+    /// The location in the source code where the value was changed.
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    /// The nodes the invalidation went through, starting with the changed value and ending with
+    /// the invalidated one.
+    pub fn path(&self) -> Vec<NodeId> {
+        self.path.iter().map(|node| node.id).collect()
+    }
+
+    pub(crate) fn enter(&mut self, node: NodeLabel) {
+        self.path.push(node);
+    }
+
+    pub(crate) fn leave(&mut self) {
+        self.path.pop();
+    }
+
+    /// Shortens the path to `len` nodes.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.path.truncate(len);
+    }
+}
+
+impl fmt::Display for InvalidationCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} changed at {}", self.path[0], self.location)?;
+        for node in &self.path[1..] {
+            write!(f, " -> {node}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{map, Runtime};
+
+    #[test]
+    fn invalidation_causes_are_not_recorded_by_default() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let b = map!(|a| a + 1);
+        b.get();
+        a.set(2);
+        assert_eq!(b.last_invalidation_cause(), None);
+    }
+
+    #[test]
+    fn invalidation_cause_records_path_and_location() {
+        let rt = Runtime::new();
+        rt.trace_invalidations(true);
+        let mut a = rt.var_named("a", 1);
+        let b = map!(|a| a + 1).named("b");
+        let c = map!(|b| b + 1).named("c");
+        c.get();
+        let line = line!() + 1;
+        a.set(2);
+
+        let cause = c.last_invalidation_cause().unwrap();
+        assert_eq!(cause.source(), a.id());
+        assert_eq!(cause.path(), [a.id(), b.id(), c.id()]);
+        assert_eq!(cause.location().file(), file!());
+        assert_eq!(cause.location().line(), line);
+        assert_eq!(
+            cause.to_string(),
+            format!(
+                "a ({}) changed at {} -> b ({}) -> c ({})",
+                a.id(),
+                cause.location(),
+                b.id(),
+                c.id()
+            )
+        );
+        assert_eq!(
+            b.last_invalidation_cause().unwrap().path(),
+            [a.id(), b.id()]
+        );
+    }
+
+    #[test]
+    fn first_change_that_reaches_a_value_is_recorded() {
+        let rt = Runtime::new();
+        rt.trace_invalidations(true);
+        let mut a = rt.var(1);
+        let mut b = rt.var(1);
+        let c = map!(|a, b| a + b);
+        c.get();
+        a.set(2);
+        // `c` is already outdated, so this change does not reach it.
+        b.set(2);
+        assert_eq!(c.last_invalidation_cause().unwrap().source(), a.id());
+        c.get();
+        b.set(3);
+        assert_eq!(c.last_invalidation_cause().unwrap().source(), b.id());
+    }
+
+    #[test]
+    fn invalidation_cause_in_batch() {
+        let rt = Runtime::new();
+        rt.trace_invalidations(true);
+        let mut a = rt.var(1);
+        let b = map!(|a| a + 1);
+        b.get();
+        let line = line!() + 1;
+        rt.batch(|| a.set(2));
+        let cause = b.last_invalidation_cause().unwrap();
+        assert_eq!(cause.path(), [a.id(), b.id()]);
+        assert_eq!(cause.location().line(), line);
+    }
+
+    #[test]
+    fn changes_through_a_lens_are_located_at_the_caller() {
+        let rt = Runtime::new();
+        rt.trace_invalidations(true);
+        let a = rt.var((1, 2));
+        let mut first = a.lens(|a| &a.0, |a, first| a.0 = first);
+        let b = map!(|a| a.1);
+        b.get();
+        let line = line!() + 1;
+        first.set(3);
+        let cause = b.last_invalidation_cause().unwrap();
+        assert_eq!(cause.source(), a.id());
+        assert_eq!(cause.location().line(), line);
+    }
+}
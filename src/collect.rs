@@ -0,0 +1,166 @@
+use crate::{graph::NodeKey, runtime::Dependencies, NodeId, Runtime};
+use std::{collections::HashMap, rc::Rc};
+
+/// The result of `Runtime::collect()`.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct CollectReport {
+    /// The values that were unreachable and were freed.
+    pub freed: Vec<NodeId>,
+    /// The values that are only kept alive by the traces of outdated values. They are freed as
+    /// soon these values are recomputed without reading them, or are dropped.
+    pub stale: Vec<NodeId>,
+}
+
+impl Runtime {
+    /// Frees the values that can't be reached from any handle, and reports the values that are
+    /// only kept alive by stale traces.
+    ///
+    /// Computed values keep the values they read alive, so that they can be verified later. If the
+    /// traces of values form a cycle, for example because a value read an outdated value while it
+    /// was borrowed, they keep each other alive after all their handles were dropped. These values are reset and freed.
+    ///
+    /// Values that are referenced by the compute function of another value are always considered
+    /// to be reachable, because these references are not known to the runtime. Use
+    /// `Value::downgrade()` to refer to values from compute functions without keeping them alive.
+    ///
+    /// Panics if a value is evaluating.
+    pub fn collect(&self) -> CollectReport {
+        assert!(
+            self.current().is_none(),
+            "Values can't be collected while a value is evaluating"
+        );
+        let graph = self.graph();
+        let nodes: Vec<_> = graph
+            .entries()
+            .filter_map(|(key, node)| Some((key, node.label.id, node.value.upgrade()?)))
+            .collect();
+
+        // The references from the traces of other values. All other references are handles.
+        let mut traced: HashMap<NodeKey, usize> = HashMap::new();
+        for (key, ..) in &nodes {
+            for &dependency in &graph.node(*key).trace {
+                *traced.entry(dependency).or_default() += 1;
+            }
+        }
+        let roots: Vec<NodeKey> = nodes
+            .iter()
+            .filter(|(key, _, node)| {
+                // One reference is held by `nodes`.
+                Rc::strong_count(node) - 1 > traced.get(key).copied().unwrap_or_default()
+            })
+            .map(|(key, ..)| *key)
+            .collect();
+        let reachable = graph.reachable(roots.iter().copied(), |_| true);
+        let fresh = graph.reachable(roots, |node| !node.outdated);
+        drop(graph);
+
+        let mut report = CollectReport::default();
+        let mut unreachable = Vec::new();
+        for (key, id, node) in nodes {
+            if !reachable.contains(&key) {
+                report.freed.push(id);
+                unreachable.push(node);
+            } else if !fresh.contains(&key) {
+                report.stale.push(id);
+            }
+        }
+
+        // Resetting the unreachable values breaks the cycles between them.
+        let dependencies: Vec<Dependencies> = unreachable
+            .iter()
+            .map(|node| node.borrow_mut().reset())
+            .collect();
+        drop(dependencies);
+        drop(unreachable);
+
+        report.freed.sort();
+        report.stale.sort();
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CollectReport, Runtime, WeakValue};
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn nothing_is_collected_from_a_reachable_graph() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let b = {
+            let a = a.clone();
+            rt.computed(move || a.get() + 1)
+        };
+        assert_eq!(b.get(), 2);
+        drop(a);
+        assert_eq!(rt.collect(), CollectReport::default());
+        assert_eq!(rt.nodes().len(), 2);
+    }
+
+    #[test]
+    fn values_that_keep_each_other_alive_are_freed() {
+        let rt = Runtime::new();
+        let mut flag = rt.var(false);
+        let late_b: Rc<RefCell<Option<WeakValue<i32>>>> = Default::default();
+        let a = {
+            let flag = flag.clone();
+            let late_b = late_b.clone();
+            rt.computed(move || match flag.get() {
+                true => late_b.borrow().as_ref().unwrap().upgrade().unwrap().get(),
+                false => 0,
+            })
+        };
+        let b = {
+            let a = a.downgrade();
+            rt.computed(move || a.upgrade().unwrap().get() + 1)
+        };
+        late_b.replace(Some(b.downgrade()));
+
+        assert_eq!(b.get(), 1);
+        {
+            // `a` reads `b` while it is borrowed, so `b` can't be updated and keeps its trace.
+            let _b = b.get_ref();
+            flag.set(true);
+            assert_eq!(a.get(), 1);
+        }
+        // `a` now reads `b`, and `b` still reads `a`.
+
+        let (a_id, b_id) = (a.id(), b.id());
+        drop(a);
+        drop(b);
+        assert_eq!(rt.nodes().len(), 3);
+        let report = rt.collect();
+        assert_eq!(report.freed, [a_id, b_id]);
+        assert_eq!(rt.nodes().len(), 1);
+        assert_eq!(rt.collect(), CollectReport::default());
+    }
+
+    #[test]
+    fn values_kept_alive_by_outdated_traces_are_stale() {
+        let rt = Runtime::new();
+        let mut flag = rt.var(true);
+        let a = rt.computed(|| 1);
+        let b = {
+            let flag = flag.clone();
+            let a = a.downgrade();
+            rt.computed(move || match flag.get() {
+                true => a.upgrade().unwrap().get(),
+                false => 0,
+            })
+        };
+        assert_eq!(b.get(), 1);
+        let a_id = a.id();
+        drop(a);
+        assert_eq!(rt.collect(), CollectReport::default());
+
+        flag.set(false);
+        let report = rt.collect();
+        assert_eq!(report.stale, [a_id]);
+        assert!(report.freed.is_empty());
+
+        assert_eq!(b.get(), 0);
+        assert_eq!(rt.collect(), CollectReport::default());
+        assert_eq!(rt.nodes().len(), 2);
+    }
+}
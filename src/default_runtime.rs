@@ -0,0 +1,103 @@
+use crate::{Runtime, Value};
+use std::cell::RefCell;
+
+thread_local! {
+    /// The default runtimes of the current thread. The last one is used.
+    static DEFAULTS: RefCell<Vec<Runtime>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Runtime {
+    /// Runs `f` with this runtime as the default runtime of the current thread.
+    ///
+    /// The default runtime is used by the free functions `var()` and `computed()`. Calls can be
+    /// nested, the innermost runtime is the default. While a value evaluates, its runtime is the
+    /// default, so values created inside `compute` closures belong to the same runtime.
+    pub fn with_default<R>(&self, f: impl FnOnce() -> R) -> R {
+        // Restores the previous default even if `f` panics.
+        struct Restore;
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                DEFAULTS.with(|defaults| defaults.borrow_mut().pop());
+            }
+        }
+
+        DEFAULTS.with(|defaults| defaults.borrow_mut().push(self.clone()));
+        let _restore = Restore;
+        f()
+    }
+
+    /// The default runtime of the current thread, if there is one.
+    pub fn default_runtime() -> Option<Runtime> {
+        DEFAULTS.with(|defaults| defaults.borrow().last().cloned())
+    }
+}
+
+/// Creates a var in the default runtime.
+///
+/// # Panics
+///
+/// If there is no default runtime. See `Runtime::with_default()`.
+pub fn var<T>(value: T) -> Value<T> {
+    expect_default_runtime().var(value)
+}
+
+/// Creates a computed value in the default runtime.
+///
+/// # Panics
+///
+/// If there is no default runtime. See `Runtime::with_default()`.
+pub fn computed<T>(compute: impl FnMut() -> T + 'static) -> Value<T> {
+    expect_default_runtime().computed(compute)
+}
+
+fn expect_default_runtime() -> Runtime {
+    Runtime::default_runtime().expect("No default runtime, see `Runtime::with_default()`")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{computed, var, Runtime};
+
+    #[test]
+    fn free_functions_use_the_default_runtime() {
+        let rt = Runtime::new();
+        let (mut a, b) = rt.with_default(|| {
+            let a = var(1);
+            let b = {
+                let a = a.clone();
+                computed(move || a.get() + 1)
+            };
+            (a, b)
+        });
+        assert!(a.runtime().ptr_eq(&rt));
+        assert_eq!(b.get(), 2);
+        a.set(2);
+        assert_eq!(b.get(), 3);
+        assert!(Runtime::default_runtime().is_none());
+    }
+
+    #[test]
+    fn innermost_default_is_used() {
+        let outer = Runtime::new();
+        let inner = Runtime::new();
+        outer.with_default(|| {
+            inner.with_default(|| assert!(var(1).runtime().ptr_eq(&inner)));
+            assert!(var(1).runtime().ptr_eq(&outer));
+        });
+    }
+
+    #[test]
+    fn values_created_while_evaluating_use_the_evaluating_runtime() {
+        let rt = Runtime::new();
+        let other = Runtime::new();
+        let c = rt.computed(|| var(1).runtime());
+        let runtime = other.with_default(|| c.get());
+        assert!(runtime.ptr_eq(&rt));
+    }
+
+    #[test]
+    #[should_panic(expected = "No default runtime")]
+    fn var_panics_without_default_runtime() {
+        var(1);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{
+    runtime::{NodeInfo, NodeState},
+    NodeId, Runtime,
+};
+use std::{collections::HashSet, fmt::Write};
+
+impl Runtime {
+    /// Exports the current dependency graph in the Graphviz DOT format.
+    ///
+    /// Every node that is alive is exported with its id, name, kind and state. The `Debug` output
+    /// of the value is included for values that were created with `Value::with_debug()`. Edges
+    /// point from a dependency to the node that read it in its last evaluation.
+    ///
+    /// Nodes that are currently evaluating can't be inspected and are left out, together with their
+    /// edges.
+    pub fn export_dot(&self) -> String {
+        let nodes: Vec<NodeInfo> = self
+            .nodes()
+            .iter()
+            .filter_map(|node| Some(node.try_borrow()?.info()))
+            .collect();
+        to_dot(&nodes)
+    }
+}
+
+/// Renders the nodes and their dependencies in the Graphviz DOT format.
+///
+/// Dependencies that are not in `nodes` are left out, Graphviz would add them as unlabeled nodes.
+pub(crate) fn to_dot(nodes: &[NodeInfo]) -> String {
+    let ids: HashSet<NodeId> = nodes.iter().map(|node| node.label.id).collect();
+    let mut dot = String::new();
+    writeln!(dot, "digraph {{").unwrap();
+    for node in nodes {
+        let mut label = format!("{} {}\n{}", node.label, node.kind, node.state);
+        if let Some(value) = &node.value {
+            write!(label, "\n{value}").unwrap();
+        }
+        let style = match node.state {
+            NodeState::Valid => "solid",
+            NodeState::Outdated | NodeState::Unevaluated => "dashed",
+            NodeState::Failed => "bold",
+        };
+        writeln!(
+            dot,
+            "    {} [label=\"{}\", style={style}];",
+            node_name(node.label.id),
+            escape(&label)
+        )
+        .unwrap();
+    }
+    for node in nodes {
+        for dependency in node.dependencies.iter().filter(|id| ids.contains(id)) {
+            writeln!(
+                dot,
+                "    {} -> {};",
+                node_name(*dependency),
+                node_name(node.label.id)
+            )
+            .unwrap();
+        }
+    }
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+fn node_name(id: NodeId) -> String {
+    format!("n{}", id.index())
+}
+
+fn escape(label: &str) -> String {
+    let mut escaped = String::with_capacity(label.len());
+    for c in label.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{map, Runtime};
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn export_dot() {
+        let rt = Runtime::new();
+        let a = rt.var_named("a", 1).with_debug();
+        let b = rt.var("b\"").with_debug();
+        let c = map!(|a, b| format!("{a}{b}")).named("c").with_debug();
+        assert_eq!(c.get(), "1b\"");
+        let d = map!(|c| c.len()).named("d");
+
+        let expected = [
+            "digraph {",
+            r##"    n0 [label="a (#0) var\nvalid\n1", style=solid];"##,
+            r##"    n1 [label="#1 var\nvalid\n\"b\\\"\"", style=solid];"##,
+            r##"    n2 [label="c (#2) computed\nvalid\n\"1b\\\"\"", style=solid];"##,
+            r##"    n3 [label="d (#3) computed\nunevaluated", style=dashed];"##,
+            "    n0 -> n2;",
+            "    n1 -> n2;",
+            "}",
+            "",
+        ];
+        assert_eq!(rt.export_dot(), expected.join("\n"));
+        drop(d);
+    }
+
+    #[test]
+    fn edges_of_evaluating_nodes_are_not_exported() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let dot = Rc::new(RefCell::new(String::new()));
+        let b = {
+            let rt = rt.clone();
+            let a = a.clone();
+            let dot = dot.clone();
+            rt.clone().computed(move || {
+                *dot.borrow_mut() = rt.export_dot();
+                a.get()
+            })
+        };
+        let c = map!(|b| b + 1);
+        let d = map!(|b| b * 10).named("d");
+        assert_eq!(c.get(), 2);
+        assert_eq!(d.get(), 10);
+
+        // `b` evaluates while `c` verifies it, both can't be exported. `d` still read `b`.
+        a.set(2);
+        assert_eq!(c.get(), 3);
+        let expected = [
+            "digraph {",
+            r##"    n0 [label="#0 var\nvalid", style=solid];"##,
+            r##"    n3 [label="d (#3) computed\noutdated", style=dashed];"##,
+            "}",
+            "",
+        ];
+        assert_eq!(*dot.borrow(), expected.join("\n"));
+    }
+
+    #[test]
+    fn dropped_nodes_are_not_exported() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        drop(a);
+        assert_eq!(rt.export_dot(), "digraph {\n}\n");
+    }
+}
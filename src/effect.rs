@@ -0,0 +1,122 @@
+use crate::{Runtime, Value};
+
+/// An effect runs a function for its side effects and re-runs it every time the values it read
+/// change.
+///
+/// Effects are not re-run immediately when a dependency changes. They are scheduled instead and
+/// re-run when `Runtime::flush_effects()` is called, or when an outermost batch commits.
+///
+/// Dropping the effect stops it from running. Effects that are created inside a scope run until
+/// the scope is dropped, see `Runtime::scope()`.
+pub struct Effect {
+    // The effect node. It is only referenced from here and from its scope, so dropping both drops
+    // the node and removes it from the readers of its dependencies.
+    _value: Value<()>,
+}
+
+impl Runtime {
+    /// Creates an effect and runs it for the first time.
+    ///
+    /// All values that are read while `run` is executed are tracked like in a computed value.
+    ///
+    /// Panics if `run` panics.
+    pub fn effect(&self, run: impl FnMut() + 'static) -> Effect {
+        let value = Value::new_effect(self, run);
+        if let Err(e) = value.update() {
+            panic!("{e}");
+        }
+        Effect { _value: value }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Runtime;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn effect_runs_on_flush() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let _effect = {
+            let a = a.clone();
+            let seen = seen.clone();
+            rt.effect(move || seen.borrow_mut().push(a.get()))
+        };
+        assert_eq!(*seen.borrow(), [1]);
+
+        a.set(2);
+        a.set(3);
+        assert_eq!(*seen.borrow(), [1]);
+        rt.flush_effects();
+        assert_eq!(*seen.borrow(), [1, 3]);
+
+        // Nothing changed, nothing runs.
+        rt.flush_effects();
+        assert_eq!(*seen.borrow(), [1, 3]);
+    }
+
+    #[test]
+    fn effect_runs_after_batch() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let mut b = rt.var(2);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let _effect = {
+            let (a, b) = (a.clone(), b.clone());
+            let seen = seen.clone();
+            rt.effect(move || seen.borrow_mut().push(a.get() + b.get()))
+        };
+
+        rt.batch(|| {
+            a.set(2);
+            b.set(3);
+        });
+        assert_eq!(*seen.borrow(), [3, 5]);
+    }
+
+    #[test]
+    fn effect_is_not_rerun_if_dependency_did_not_change() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let parity = {
+            let a = a.clone();
+            rt.computed_eq(move || a.get() % 2)
+        };
+        let runs = Rc::new(RefCell::new(0));
+        let _effect = {
+            let runs = runs.clone();
+            rt.effect(move || {
+                parity.track();
+                *runs.borrow_mut() += 1;
+            })
+        };
+
+        a.set(3);
+        rt.flush_effects();
+        assert_eq!(*runs.borrow(), 1);
+    }
+
+    #[test]
+    fn dropped_effect_does_not_run() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let runs = Rc::new(RefCell::new(0));
+        let effect = {
+            let a = a.clone();
+            let runs = runs.clone();
+            rt.effect(move || {
+                a.track();
+                *runs.borrow_mut() += 1;
+            })
+        };
+        assert_eq!(a.readers_count(), 1);
+
+        a.set(2);
+        drop(effect);
+        assert_eq!(a.readers_count(), 0);
+        rt.flush_effects();
+        assert_eq!(*runs.borrow(), 1);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::runtime::{NodeId, NodeLabel};
+use std::{any::Any, error, fmt, sync::Arc};
+
+/// A value was read while it was evaluating.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CycleError {
+    nodes: Vec<NodeLabel>,
+}
+
+impl CycleError {
+    pub(crate) fn new(nodes: Vec<NodeLabel>) -> Self {
+        debug_assert!(!nodes.is_empty());
+        CycleError { nodes }
+    }
+
+    /// The nodes that participate in the cycle, starting with the node that was read again,
+    /// followed by the nodes it (indirectly) read from in evaluation order.
+    pub fn nodes(&self) -> Vec<NodeId> {
+        self.nodes.iter().map(|node| node.id).collect()
+    }
+}
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cycle detected: ")?;
+        for node in &self.nodes {
+            write!(f, "{node} -> ")?;
+        }
+        write!(f, "{}", self.nodes[0])
+    }
+}
+
+impl error::Error for CycleError {}
+
+/// The evaluation of a value panicked.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PoisonError {
+    node: NodeLabel,
+    message: Arc<str>,
+}
+
+impl PoisonError {
+    pub(crate) fn new(node: NodeLabel, payload: Box<dyn Any + Send>) -> Self {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            (*message).into()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.as_str().into()
+        } else {
+            "Box<dyn Any>".into()
+        };
+        PoisonError { node, message }
+    }
+
+    /// The node whose evaluation panicked.
+    pub fn node(&self) -> NodeId {
+        self.node.id
+    }
+
+    /// The panic message.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for PoisonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Evaluation of {} panicked: {}", self.node, self.message)
+    }
+}
+
+impl error::Error for PoisonError {}
+
+/// A computed value was read after its scope was disposed, but it was never evaluated.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DisposedError {
+    node: NodeLabel,
+}
+
+impl DisposedError {
+    pub(crate) fn new(node: NodeLabel) -> Self {
+        DisposedError { node }
+    }
+
+    /// The node that was disposed.
+    pub fn node(&self) -> NodeId {
+        self.node.id
+    }
+}
+
+impl fmt::Display for DisposedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} was disposed before it was evaluated", self.node)
+    }
+}
+
+impl error::Error for DisposedError {}
+
+/// The reasons why a value can't be retrieved.
+#[derive(Clone, Debug)]
+pub enum Error {
+    Cycle(CycleError),
+    Poisoned(PoisonError),
+    /// A fallible computed value returned an error.
+    Failed(Arc<dyn error::Error + Send + Sync>),
+    /// A computed value that was never evaluated was read after its scope was disposed.
+    Disposed(DisposedError),
+}
+
+impl Error {
+    /// Creates an error for a failed computation.
+    pub fn failed(error: impl error::Error + Send + Sync + 'static) -> Self {
+        Error::Failed(Arc::new(error))
+    }
+}
+
+impl From<CycleError> for Error {
+    fn from(error: CycleError) -> Self {
+        Error::Cycle(error)
+    }
+}
+
+impl From<PoisonError> for Error {
+    fn from(error: PoisonError) -> Self {
+        Error::Poisoned(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Cycle(e) => e.fmt(f),
+            Error::Poisoned(e) => e.fmt(f),
+            Error::Failed(e) => e.fmt(f),
+            Error::Disposed(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Cycle(e) => Some(e),
+            Error::Poisoned(e) => Some(e),
+            Error::Failed(e) => Some(&**e),
+            Error::Disposed(e) => Some(e),
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use crate::{
+    runtime::{NodeKind, NodeLabel, RefCellNode},
+    InvalidationCause,
+};
+use std::{collections::HashSet, rc::Weak};
+
+/// Identifies a node in the graph of a runtime.
+///
+/// Slots are reused after their node was dropped. The generation distinguishes the nodes that
+/// occupied the same slot, so that a key of a dropped node never refers to a newer one.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct NodeKey {
+    index: u32,
+    generation: u32,
+}
+
+pub(crate) type Readers = HashSet<NodeKey>;
+pub(crate) type Trace = Vec<NodeKey>;
+
+/// The dependency graph of a runtime.
+///
+/// All the bookkeeping that connects nodes is stored here, so that invalidation can walk the
+/// graph without touching the values, which might be borrowed while they are evaluating.
+#[derive(Default)]
+pub(crate) struct Graph {
+    slots: Vec<Slot>,
+    /// Indices of slots that are not occupied.
+    free: Vec<u32>,
+}
+
+#[derive(Default)]
+struct Slot {
+    generation: u32,
+    node: Option<GraphNode>,
+}
+
+pub(crate) struct GraphNode {
+    pub label: NodeLabel,
+    pub kind: NodeKind,
+    /// The value of the node.
+    pub value: Weak<dyn RefCellNode>,
+    /// The nodes that read from this node.
+    pub readers: Readers,
+    /// The nodes this node read from in its last evaluation, in the order they were first read.
+    pub trace: Trace,
+    /// Set when a dependency might have changed. An outdated value is verified against its
+    /// dependencies before it is used again. Only set for computed values.
+    pub outdated: bool,
+    /// Why the node was invalidated the last time, if invalidation tracing is enabled.
+    pub invalidation_cause: Option<InvalidationCause>,
+}
+
+impl GraphNode {
+    pub fn new(label: NodeLabel, kind: NodeKind, value: Weak<dyn RefCellNode>) -> Self {
+        GraphNode {
+            label,
+            kind,
+            value,
+            readers: Readers::new(),
+            trace: Trace::new(),
+            outdated: false,
+            invalidation_cause: None,
+        }
+    }
+}
+
+impl Graph {
+    pub fn insert(&mut self, node: GraphNode) -> NodeKey {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot::default());
+                (self.slots.len() - 1) as u32
+            }
+        };
+        let slot = &mut self.slots[index as usize];
+        slot.node = Some(node);
+        NodeKey {
+            index,
+            generation: slot.generation,
+        }
+    }
+
+    /// Removes the node and removes it from the readers of its dependencies.
+    pub fn remove(&mut self, key: NodeKey) {
+        let Some(node) = self.slot_mut(key).and_then(|slot| slot.node.take()) else {
+            return;
+        };
+        debug_assert!(node.readers.is_empty());
+        let slot = &mut self.slots[key.index as usize];
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(key.index);
+        self.unlink(key, &node.trace);
+    }
+
+    /// The number of nodes.
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn get(&self, key: NodeKey) -> Option<&GraphNode> {
+        let slot = self.slots.get(key.index as usize)?;
+        if slot.generation != key.generation {
+            return None;
+        }
+        slot.node.as_ref()
+    }
+
+    /// The node of `key`. Panics if the node was removed.
+    pub fn node(&self, key: NodeKey) -> &GraphNode {
+        self.get(key).expect("Node was removed")
+    }
+
+    /// The node of `key`. Panics if the node was removed.
+    pub fn node_mut(&mut self, key: NodeKey) -> &mut GraphNode {
+        self.get_mut(key).expect("Node was removed")
+    }
+
+    fn get_mut(&mut self, key: NodeKey) -> Option<&mut GraphNode> {
+        self.slot_mut(key)?.node.as_mut()
+    }
+
+    fn slot_mut(&mut self, key: NodeKey) -> Option<&mut Slot> {
+        let slot = self.slots.get_mut(key.index as usize)?;
+        (slot.generation == key.generation).then_some(slot)
+    }
+
+    /// All nodes in slot order.
+    pub fn nodes(&self) -> impl Iterator<Item = &GraphNode> {
+        self.entries().map(|(_, node)| node)
+    }
+
+    /// All nodes with their keys in slot order.
+    pub fn entries(&self) -> impl Iterator<Item = (NodeKey, &GraphNode)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let key = NodeKey {
+                index: index as u32,
+                generation: slot.generation,
+            };
+            Some((key, slot.node.as_ref()?))
+        })
+    }
+
+    /// The nodes that can be reached from `roots` by following the traces of the nodes for which
+    /// `follow` returns `true`.
+    pub fn reachable(
+        &self,
+        roots: impl IntoIterator<Item = NodeKey>,
+        follow: impl Fn(&GraphNode) -> bool,
+    ) -> HashSet<NodeKey> {
+        let mut reachable = HashSet::new();
+        let mut pending: Vec<NodeKey> = roots.into_iter().collect();
+        while let Some(key) = pending.pop() {
+            if !reachable.insert(key) {
+                continue;
+            }
+            let node = self.node(key);
+            if follow(node) {
+                pending.extend(node.trace.iter().copied());
+            }
+        }
+        reachable
+    }
+
+    /// Records that `reader` read from `dependency`. Returns `false` if `reader` already read from
+    /// it since its trace was cleared.
+    pub fn add_edge(&mut self, reader: NodeKey, dependency: NodeKey) -> bool {
+        // A reader is removed from the readers of its dependencies when its trace is cleared, so
+        // it is only found here if it read the dependency before in the current evaluation.
+        if !self.node_mut(dependency).readers.insert(reader) {
+            return false;
+        }
+        self.node_mut(reader).trace.push(dependency);
+        true
+    }
+
+    /// Clears the trace of `key` and removes it from the readers of its dependencies.
+    pub fn clear_trace(&mut self, key: NodeKey) {
+        let trace = std::mem::take(&mut self.node_mut(key).trace);
+        self.unlink(key, &trace);
+    }
+
+    fn unlink(&mut self, reader: NodeKey, trace: &Trace) {
+        for &dependency in trace {
+            if let Some(dependency) = self.get_mut(dependency) {
+                dependency.readers.remove(&reader);
+            }
+        }
+    }
+
+    /// Marks all nodes that transitively read from `key` as outdated and returns the effects
+    /// among them. `key` itself is marked too if it's a computed value.
+    ///
+    /// Values are kept and only marked as outdated. When they are needed again, they are verified
+    /// against their trace, and recomputed only if one of their dependencies actually changed.
+    pub fn invalidate(
+        &mut self,
+        key: NodeKey,
+        mut cause: Option<&mut InvalidationCause>,
+    ) -> Vec<Weak<dyn RefCellNode>> {
+        let mut effects = Vec::new();
+        // The nodes to visit with the length of their path from `key`.
+        let mut pending = vec![(key, 0)];
+        while let Some((key, depth)) = pending.pop() {
+            let Some(node) = self.get_mut(key) else {
+                continue;
+            };
+            if node.kind != NodeKind::Var {
+                if node.outdated {
+                    // Readers of an outdated node are already marked as outdated.
+                    continue;
+                }
+                node.outdated = true;
+                if node.kind == NodeKind::Effect {
+                    effects.push(node.value.clone());
+                }
+            }
+            if let Some(cause) = cause.as_deref_mut() {
+                cause.truncate(depth);
+                cause.enter(node.label.clone());
+                if node.kind != NodeKind::Var {
+                    #[cfg(feature = "log")]
+                    log::debug!("Invalidated: {cause}");
+                    node.invalidation_cause = Some(cause.clone());
+                }
+            }
+            pending.extend(node.readers.iter().map(|&reader| (reader, depth + 1)));
+        }
+        effects
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Runtime;
+
+    #[test]
+    fn slots_are_reused_with_a_new_generation() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let key = a.key();
+        drop(a);
+        let b = rt.var(2);
+        assert_ne!(b.key(), key);
+        assert!(rt.graph().get(key).is_none());
+        assert_eq!(rt.graph().slots.len(), 1);
+    }
+}
@@ -1,18 +1,43 @@
+mod cause;
+mod collect;
+mod default_runtime;
+mod dot;
+mod effect;
+mod error;
+mod graph;
+mod query;
+mod query_table;
+mod reactive_vec;
 mod runtime;
+mod scope;
 mod stream;
 mod stream_value;
+pub mod sync;
 mod value;
 
-pub use granularity_macros::{map, memo};
-pub use runtime::Runtime;
+pub use cause::InvalidationCause;
+pub use collect::CollectReport;
+pub use default_runtime::{computed, var};
+pub use effect::Effect;
+pub use error::{CycleError, DisposedError, Error, PoisonError};
+pub use granularity_macros::{map, memo, query, try_map, Reactive};
+pub use query_table::{QueryStats, QueryTable};
+pub use reactive_vec::{ReactiveVec, VecDiff};
+pub use runtime::{NodeId, Runtime};
+pub use scope::Scope;
 pub use stream_value::*;
-pub use value::Value;
+pub use value::{Value, WeakValue};
+
+// Lets the `::granularity` paths generated by `#[derive(Reactive)]` resolve inside this crate.
+extern crate self as granularity;
 
 #[cfg(test)]
 mod tests {
-    use crate::{map, memo, runtime::Runtime};
+    use crate::{map, memo, runtime::Runtime, try_map, Error, Reactive, Value};
     use std::{
         cell::{Cell, RefCell},
+        fmt,
+        panic::{self, AssertUnwindSafe},
         rc::Rc,
     };
 
@@ -229,4 +254,928 @@ mod tests {
         // And `a` must be read twice.
         assert_eq!(a.readers_count(), 2);
     }
+
+    #[test]
+    fn batch_invalidates_readers_once() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let mut b = rt.var(2);
+        let evaluation_count = Rc::new(Cell::new(0));
+        let c = {
+            let ec = evaluation_count.clone();
+            map!(|a, b| {
+                ec.set(ec.get() + 1);
+                a + b
+            })
+        };
+        assert_eq!(c.get(), 3);
+
+        rt.batch(|| {
+            a.set(2);
+            b.set(3);
+            // Invalidation is postponed until the batch commits.
+            assert!(c.is_valid());
+            assert_eq!(a.readers_count(), 1);
+        });
+
+        assert!(!c.is_valid());
+        assert_eq!(c.get(), 5);
+        assert_eq!(evaluation_count.get(), 2);
+    }
+
+    #[test]
+    fn nested_batches_commit_with_the_outermost_batch() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let c = map!(|a| a * 2);
+        assert_eq!(c.get(), 2);
+
+        rt.batch(|| {
+            rt.batch(|| a.set(2));
+            assert_eq!(c.get(), 2);
+        });
+
+        assert_eq!(c.get(), 4);
+    }
+
+    #[test]
+    fn panicking_batch_commits_changes() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let c = map!(|a| a * 2);
+        assert_eq!(c.get(), 2);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            rt.batch(|| {
+                let mut a = a.clone();
+                a.set(2);
+                panic!("inside batch");
+            })
+        }));
+
+        assert!(result.is_err());
+        assert!(!rt.is_batching());
+        assert_eq!(c.get(), 4);
+    }
+
+    #[test]
+    fn var_eq_does_not_invalidate_on_equal_value() {
+        let rt = Runtime::new();
+        let mut a = rt.var_eq(1);
+        let count = Rc::new(Cell::new(0));
+        let c = {
+            let count = count.clone();
+            map!(|a| {
+                count.set(count.get() + 1);
+                a + 1
+            })
+        };
+
+        assert_eq!(c.get(), 2);
+        a.set(1);
+        assert!(c.is_valid());
+        assert_eq!(c.get(), 2);
+        assert_eq!(count.get(), 1);
+
+        a.set(2);
+        assert_eq!(c.get(), 3);
+        assert_eq!(count.get(), 2);
+    }
+
+    #[test]
+    fn set_if_changed() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let c = map!(|a| a + 1);
+        assert_eq!(c.get(), 2);
+        a.set_if_changed(1);
+        assert!(c.is_valid());
+        a.set_if_changed(2);
+        assert!(!c.is_valid());
+        assert_eq!(c.get(), 3);
+    }
+
+    #[test]
+    fn computed_eq_cuts_off_recomputation() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let parity = {
+            let a = a.clone();
+            rt.computed_eq(move || a.get() % 2)
+        };
+        let count = Rc::new(Cell::new(0));
+        let c = {
+            let count = count.clone();
+            map!(|parity| {
+                count.set(count.get() + 1);
+                *parity
+            })
+        };
+
+        assert_eq!(c.get(), 1);
+        assert_eq!(count.get(), 1);
+
+        // `parity` gets recomputed, but does not change, so `c` stays the same.
+        a.set(3);
+        assert!(!c.is_valid());
+        assert_eq!(c.get(), 1);
+        assert_eq!(count.get(), 1);
+        assert!(parity.is_valid());
+
+        a.set(4);
+        assert_eq!(c.get(), 0);
+        assert_eq!(count.get(), 2);
+    }
+
+    /// Dependencies that are not equality-checked still cause a recomputation in a diamond, even
+    /// if an equality-checked one did not change.
+    #[test]
+    fn computed_eq_in_diamond() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let parity = {
+            let a = a.clone();
+            rt.computed_eq(move || a.get() % 2)
+        };
+        let r = map!(|parity, a| parity + a);
+        assert_eq!(r.get(), 2);
+        a.set(3);
+        assert_eq!(r.get(), 4);
+    }
+
+    /// A change that is cut off at the start of a deep chain does not cause any recomputation of
+    /// the chain, the values are verified instead.
+    #[test]
+    fn deep_chain_is_verified_without_recomputation() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let parity = {
+            let a = a.clone();
+            rt.computed_eq(move || a.get() % 2)
+        };
+        let count = Rc::new(Cell::new(0));
+        let mut chain = parity.clone();
+        for _ in 0..10 {
+            let count = count.clone();
+            chain = map!(|chain| {
+                count.set(count.get() + 1);
+                chain + 1
+            });
+        }
+
+        assert_eq!(chain.get(), 11);
+        assert_eq!(count.get(), 10);
+
+        a.set(3);
+        assert_eq!(chain.get(), 11);
+        assert_eq!(count.get(), 10);
+
+        a.set(2);
+        assert_eq!(chain.get(), 10);
+        assert_eq!(count.get(), 20);
+    }
+
+    /// A var that is changed while a computed value reads it, causes the computed value to be
+    /// recomputed the next time it is read.
+    #[test]
+    fn var_changed_while_computing() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let c = {
+            let a = a.clone();
+            rt.computed(move || {
+                let r = a.get();
+                if r == 1 {
+                    a.clone().set(2);
+                }
+                r
+            })
+        };
+        assert_eq!(c.get(), 1);
+        assert!(!c.is_valid());
+        assert_eq!(c.get(), 2);
+        assert!(c.is_valid());
+    }
+
+    /// A reader is not verified while one of its outdated dependencies is borrowed, and is
+    /// recomputed once the reference is dropped.
+    #[test]
+    fn reader_of_borrowed_outdated_value_is_verified_later() {
+        let rt = Runtime::new();
+        let mut x = rt.var(1);
+        let a = map!(|*x| x * 2);
+        let b = map!(|*a| a + 1);
+        assert_eq!(b.get(), 3);
+
+        let r = a.get_ref();
+        x.set(5);
+        assert_eq!(b.get(), 3);
+        assert!(!b.is_valid());
+        drop(r);
+        assert_eq!(b.get(), 11);
+    }
+
+    /// A value that reads an outdated value while it is borrowed gets the current value, but stays
+    /// outdated until the reference is dropped.
+    #[test]
+    fn value_reading_borrowed_outdated_value_stays_outdated() {
+        let rt = Runtime::new();
+        let mut x = rt.var(1);
+        let a = map!(|*x| x * 2);
+        assert_eq!(a.get(), 2);
+
+        let r = a.get_ref();
+        x.set(5);
+        let b = map!(|*a| a + 1);
+        assert_eq!(b.get(), 3);
+        assert!(!b.is_valid());
+        drop(r);
+        assert_eq!(b.get(), 11);
+    }
+
+    /// A value that is set after the values that refer to it are created. Used to create cycles.
+    ///
+    /// Dropping a handle clears the value, so that the values in the cycle are freed.
+    struct Late<T: 'static>(Rc<RefCell<Option<Value<T>>>>);
+
+    impl<T> Late<T> {
+        fn new() -> Self {
+            Late(Default::default())
+        }
+
+        fn set(&self, value: Value<T>) {
+            *self.0.borrow_mut() = Some(value);
+        }
+
+        fn get(&self) -> Value<T> {
+            self.0.borrow().clone().unwrap()
+        }
+    }
+
+    impl<T> Clone for Late<T> {
+        fn clone(&self) -> Self {
+            Late(self.0.clone())
+        }
+    }
+
+    impl<T> Drop for Late<T> {
+        fn drop(&mut self) {
+            let value = self.0.borrow_mut().take();
+            drop(value);
+        }
+    }
+
+    #[test]
+    fn self_cycle_is_detected() {
+        let rt = Runtime::new();
+        let this: Late<Result<i32, Error>> = Late::new();
+        let a = {
+            let this = this.clone();
+            rt.computed(move || {
+                let _ = this.get().try_get()?;
+                Ok(1)
+            })
+        };
+        this.set(a.clone());
+
+        let Err(Error::Cycle(error)) = a.get() else {
+            panic!("cycle expected");
+        };
+        assert_eq!(error.nodes(), [a.id()]);
+    }
+
+    #[test]
+    fn cycle_is_detected() {
+        let rt = Runtime::new();
+        let late_a: Late<i32> = Late::new();
+        let b = {
+            let late_a = late_a.clone();
+            rt.computed(move || late_a.get().try_get().map(|a| a + 1))
+                .named("b")
+        };
+        let a = map!(|b| b.clone().unwrap_or(0)).named("a");
+        late_a.set(a.clone());
+
+        assert_eq!(a.get(), 0);
+        let Err(Error::Cycle(error)) = b.get() else {
+            panic!("cycle expected");
+        };
+        assert_eq!(error.nodes(), [a.id(), b.id()]);
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Cycle detected: a ({}) -> b ({}) -> a ({})",
+                a.id(),
+                b.id(),
+                a.id()
+            )
+        );
+    }
+
+    /// A cycle that is closed by a dependency that changes between revisions is detected while
+    /// the values are verified.
+    #[test]
+    fn cycle_closed_by_a_changed_dependency_is_detected() {
+        let rt = Runtime::new();
+        let mut closed = rt.var(false);
+        let late_a: Late<i32> = Late::new();
+        let b = {
+            let late_a = late_a.clone();
+            let closed = closed.clone();
+            rt.computed(move || match closed.get() {
+                true => late_a.get().try_get().map(|a| a + 1),
+                false => Ok(0),
+            })
+            .named("b")
+        };
+        let a = map!(|b| b.clone().unwrap_or(0)).named("a");
+        late_a.set(a.clone());
+        assert_eq!(a.get(), 0);
+
+        // `a` is verified first, `b` reads it while `a` verifies its dependencies.
+        closed.set(true);
+        assert_eq!(a.get(), 0);
+        let Err(Error::Cycle(error)) = b.get() else {
+            panic!("cycle expected");
+        };
+        assert_eq!(error.nodes(), [a.id(), b.id()]);
+
+        // `b` is verified first and reads `a`, which verifies `b` while it is evaluating. `a`
+        // reads `b` with `get()` and panics with the cycle.
+        closed.set(false);
+        assert_eq!(b.get().unwrap(), 0);
+        assert_eq!(a.get(), 0);
+        closed.set(true);
+        let Err(Error::Poisoned(error)) = b.get() else {
+            panic!("poisoned value expected");
+        };
+        assert_eq!(error.node(), a.id());
+        assert_eq!(
+            error.message(),
+            format!(
+                "Cycle detected: b ({}) -> a ({}) -> b ({})",
+                b.id(),
+                a.id(),
+                b.id()
+            )
+        );
+        assert!(matches!(a.try_get(), Err(Error::Poisoned(_))));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cycle detected")]
+    fn get_panics_on_cycle() {
+        let rt = Runtime::new();
+        let late_a: Late<i32> = Late::new();
+        let a = {
+            let late_a = late_a.clone();
+            rt.computed(move || late_a.get().get())
+        };
+        late_a.set(a.clone());
+        a.get();
+    }
+
+    #[test]
+    fn cycle_fallback() {
+        let rt = Runtime::new();
+        let late_a: Late<i32> = Late::new();
+        let late_b: Late<i32> = Late::new();
+        let a = {
+            let late_b = late_b.clone();
+            rt.computed_with_cycle_fallback(move || late_b.get().get() + 1, 0)
+        };
+        let b = {
+            let late_a = late_a.clone();
+            rt.computed(move || late_a.get().get() * 2 + 1)
+        };
+        late_a.set(a.clone());
+        late_b.set(b.clone());
+
+        // `b` reads the fallback of `a`.
+        assert_eq!(a.get(), 2);
+        assert_eq!(b.get(), 1);
+    }
+
+    #[test]
+    fn panicking_computed_is_poisoned_until_an_input_changes() {
+        let rt = Runtime::new();
+        let mut a = rt.var(0);
+        let runs = Rc::new(Cell::new(0));
+        let c = {
+            let runs = runs.clone();
+            map!(|*a| {
+                runs.set(runs.get() + 1);
+                if a == 0 {
+                    panic!("division by zero");
+                }
+                10 / a
+            })
+        };
+
+        let Err(Error::Poisoned(error)) = c.try_get() else {
+            panic!("poisoned value expected");
+        };
+        assert_eq!(error.node(), c.id());
+        assert_eq!(error.message(), "division by zero");
+        assert!(rt.current().is_none());
+
+        // The poisoned value is not recomputed.
+        assert!(c.try_get().is_err());
+        assert_eq!(runs.get(), 1);
+
+        a.set(2);
+        assert_eq!(c.get(), 5);
+        assert_eq!(runs.get(), 2);
+    }
+
+    #[test]
+    fn poisoned_value_is_invalidated_by_the_dependencies_read_before_the_panic() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let mut b = rt.var(10);
+        let runs = Rc::new(Cell::new(0));
+        let c = {
+            let (a, b) = (a.clone(), b.clone());
+            let runs = runs.clone();
+            rt.computed(move || {
+                runs.set(runs.get() + 1);
+                let a = a.get();
+                if a == 0 {
+                    panic!("division by zero");
+                }
+                b.get() / a
+            })
+        };
+        assert_eq!(c.get(), 10);
+        assert_eq!(b.readers_count(), 1);
+
+        a.set(0);
+        assert!(c.try_get().is_err());
+        assert_eq!(runs.get(), 2);
+        // `b` was read by the previous run only, so it does not invalidate the poisoned value.
+        assert_eq!(b.readers_count(), 0);
+        b.set(20);
+        assert!(c.try_get().is_err());
+        assert_eq!(runs.get(), 2);
+
+        a.set(2);
+        assert_eq!(c.get(), 10);
+        assert_eq!(runs.get(), 3);
+        assert_eq!(b.readers_count(), 1);
+    }
+
+    #[test]
+    fn get_of_poisoned_value_panics() {
+        let rt = Runtime::new();
+        let c = rt.computed(|| -> i32 { panic!("boom") });
+        assert!(c.try_get().is_err());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| c.get()));
+        let message = result.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.ends_with("panicked: boom"));
+    }
+
+    /// A panic in a dependency poisons all values that read it, and all of them recover when the
+    /// dependency's input changes.
+    #[test]
+    fn poison_propagates_to_readers() {
+        let rt = Runtime::new();
+        let mut a = rt.var(0);
+        let b = map!(|*a| {
+            assert!(a != 0, "zero");
+            a
+        });
+        let c = map!(|*b| b + 1);
+
+        let Err(Error::Poisoned(error)) = c.try_get() else {
+            panic!("poisoned value expected");
+        };
+        assert_eq!(error.node(), c.id());
+        assert!(error.message().ends_with("panicked: zero"));
+        assert_eq!(b.readers_count(), 1);
+
+        a.set(1);
+        assert_eq!(c.get(), 2);
+    }
+
+    #[derive(Debug)]
+    struct NegativeError;
+
+    impl fmt::Display for NegativeError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "negative")
+        }
+    }
+
+    impl std::error::Error for NegativeError {}
+
+    #[test]
+    fn failed_computed_is_cached_until_an_input_changes() {
+        let rt = Runtime::new();
+        let mut a = rt.var(-1);
+        let runs = Rc::new(Cell::new(0));
+        let sqrt = {
+            let a = a.clone();
+            let runs = runs.clone();
+            rt.try_computed(move || {
+                runs.set(runs.get() + 1);
+                let a = a.get();
+                if a < 0 {
+                    return Err(Error::failed(NegativeError));
+                }
+                Ok((a as f64).sqrt())
+            })
+        };
+        let doubled = try_map!(|*sqrt| Ok(sqrt * 2.0));
+
+        let Err(Error::Failed(error)) = doubled.try_get() else {
+            panic!("failure expected");
+        };
+        assert_eq!(error.to_string(), "negative");
+        assert!(doubled.try_get().is_err());
+        assert_eq!(runs.get(), 1);
+
+        a.set(4);
+        assert_eq!(doubled.try_get().unwrap(), 4.0);
+        assert_eq!(runs.get(), 2);
+    }
+
+    #[test]
+    fn try_map_macro() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let b = rt.var(String::from("2"));
+        let c = try_map!(|*a, b| {
+            let b: i32 = b.parse().map_err(Error::failed)?;
+            Ok(a + b)
+        });
+        assert_eq!(c.try_get().unwrap(), 3);
+        b.clone().set("x".into());
+        assert!(matches!(c.try_get(), Err(Error::Failed(_))));
+    }
+
+    #[test]
+    fn names() {
+        let rt = Runtime::new();
+        let a = rt.var_named("a", 1);
+        let b = rt.var(2);
+        let (c, line) = (map!(|a, b| a + b), line!());
+        assert_eq!(a.name().as_deref(), Some("a"));
+        assert_eq!(b.name(), None);
+        assert_eq!(c.name().unwrap().to_string(), format!("{}:{line}", file!()));
+    }
+
+    #[test]
+    fn debug_and_display() {
+        let rt = Runtime::new();
+        let a = rt.var_named("a", 1);
+        let b = map!(|a| a + 1).named("b");
+        assert_eq!(
+            format!("{a:?}"),
+            format!(
+                r#"Value {{ id: {:?}, name: Some("a"), kind: var, state: valid, value: 1 }}"#,
+                a.id()
+            )
+        );
+        assert_eq!(
+            format!("{b:?}"),
+            format!(
+                r#"Value {{ id: {:?}, name: Some("b"), kind: computed, state: unevaluated }}"#,
+                b.id()
+            )
+        );
+        assert_eq!(b.get(), 2);
+        assert_eq!(
+            format!("{b:?}"),
+            format!(
+                r#"Value {{ id: {:?}, name: Some("b"), kind: computed, state: valid, value: 2 }}"#,
+                b.id()
+            )
+        );
+        assert_eq!(b.to_string(), format!("b ({}): computed, valid", b.id()));
+    }
+
+    #[test]
+    fn untracked_reads_are_not_dependencies() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let mut b = rt.var(2);
+        let c = rt.var(3);
+        let sum = {
+            let (a, b, c) = (a.clone(), b.clone(), c.clone());
+            rt.computed(move || a.get() + b.get_untracked() + *c.peek())
+        };
+
+        assert_eq!(sum.get(), 6);
+        assert_eq!(sum.trace_len(), 1);
+        assert_eq!(a.readers_count(), 1);
+        assert_eq!(b.readers_count(), 0);
+        assert_eq!(c.readers_count(), 0);
+
+        b.set(3);
+        assert!(sum.is_valid());
+        assert_eq!(sum.get(), 6);
+
+        a.set(2);
+        assert_eq!(sum.get(), 8);
+    }
+
+    #[test]
+    fn untrack_scope() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let mut b = rt.var(2);
+        let b2 = map!(|b| b * 2);
+        let sum = {
+            let rt = rt.clone();
+            let (a, b2) = (a.clone(), b2.clone());
+            rt.clone()
+                .computed(move || a.get() + rt.untrack(|| b2.get()))
+        };
+
+        assert_eq!(sum.get(), 5);
+        assert_eq!(sum.trace_len(), 1);
+        assert_eq!(b2.readers_count(), 0);
+        // Values evaluated inside the scope still track their own reads.
+        assert_eq!(b.readers_count(), 1);
+        assert_eq!(b2.trace_len(), 1);
+
+        b.set(3);
+        assert!(!b2.is_valid());
+        assert!(sum.is_valid());
+        assert_eq!(sum.get(), 5);
+    }
+
+    #[test]
+    fn untracked_reads_outside_of_evaluations() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let b = map!(|a| a + 1);
+        assert_eq!(*b.peek(), 2);
+        assert_eq!(b.get_untracked(), 2);
+        assert_eq!(rt.untrack(|| b.get()), 2);
+    }
+
+    /// Values that are read in a hot loop are recorded once per evaluation, so the trace stays
+    /// bounded by the number of distinct dependencies.
+    #[test]
+    fn hot_loop_reads_are_traced_once() {
+        const READS: i32 = 1000;
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let b = rt.var(2);
+        let a2 = map!(|a| a * 2);
+        let sum = {
+            let (a, b, a2) = (a.clone(), b.clone(), a2.clone());
+            rt.computed(move || {
+                (0..READS)
+                    .map(|_| a.get() + b.get() + a2.get())
+                    .sum::<i32>()
+            })
+        };
+
+        for i in 1..10 {
+            a.set(i);
+            assert_eq!(sum.get(), (i * 3 + 2) * READS);
+            assert_eq!(sum.trace_len(), 3);
+            assert_eq!(a.readers_count(), 2);
+            assert_eq!(b.readers_count(), 1);
+        }
+    }
+
+    #[test]
+    fn computed_with_prev() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let history = {
+            let a = a.clone();
+            rt.computed_with_prev(move |prev: Option<Vec<i32>>| {
+                let mut history = prev.unwrap_or_default();
+                history.push(a.get());
+                history
+            })
+        };
+
+        assert_eq!(history.get(), [1]);
+        a.set(2);
+        a.set(3);
+        assert_eq!(history.get(), [1, 3]);
+        // The previous value is lost when it is taken.
+        assert_eq!(history.clone().take(), [1, 3]);
+        assert_eq!(history.get(), [3]);
+    }
+
+    #[test]
+    fn fold() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let sum = {
+            let a = a.clone();
+            rt.fold(10, move |sum| sum + a.get())
+        };
+        assert_eq!(sum.get(), 11);
+        assert_eq!(sum.get(), 11);
+        a.set(2);
+        assert_eq!(sum.get(), 13);
+        a.set(3);
+        assert_eq!(sum.get(), 16);
+    }
+
+    #[test]
+    fn consumer_receives_produced_values() {
+        let rt = Runtime::new();
+        let mut producer = rt.producer();
+        producer.produce(1);
+        let consumer = producer.subscribe();
+        producer.produce(2);
+        producer.produce(3);
+        assert_eq!(consumer.get_ref().drain().collect::<Vec<_>>(), [2, 3]);
+        assert_eq!(consumer.get_ref().drain().count(), 0);
+        producer.produce(4);
+        assert!(!consumer.is_valid());
+        assert_eq!(consumer.get_ref().drain().collect::<Vec<_>>(), [4]);
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Window {
+        title: String,
+        size: (u32, u32),
+    }
+
+    #[test]
+    fn lens() {
+        let rt = Runtime::new();
+        let window = rt.var(Window {
+            title: "untitled".into(),
+            size: (640, 480),
+        });
+        let mut title = window.lens(|w| &w.title, |w, title| w.title = title);
+        let mut width = window
+            .lens(|w| &w.size, |w, size| w.size = size)
+            .lens(|s| &s.0, |s, width| s.0 = width);
+        let area = map!(|window| window.size.0 * window.size.1);
+
+        assert_eq!(title.get(), "untitled");
+        assert_eq!(width.get(), 640);
+        assert_eq!(area.get(), 640 * 480);
+
+        title.set("granularity".into());
+        assert_eq!(window.get_ref().title, "granularity");
+        assert_eq!(title.get(), "granularity");
+
+        width.apply(|width| width * 2);
+        assert_eq!(window.get_ref().size, (1280, 480));
+        assert_eq!(width.get(), 1280);
+        assert_eq!(area.get(), 1280 * 480);
+    }
+
+    #[test]
+    fn lens_set_if_changed_compares_with_the_source() {
+        let rt = Runtime::new();
+        let mut window = rt.var(Window {
+            title: "a".into(),
+            size: (1, 1),
+        });
+        let mut title = window.lens(|w| &w.title, |w, title| w.title = title);
+        let reader = map!(|window| window.size);
+        assert_eq!(title.get(), "a");
+        assert_eq!(reader.get(), (1, 1));
+
+        // The lens still holds "a".
+        window.apply(|w| Window {
+            title: "b".into(),
+            ..w
+        });
+        assert_eq!(reader.get(), (1, 1));
+        title.set_if_changed("b".into());
+        assert!(reader.is_valid());
+        title.set_if_changed("a".into());
+        assert!(!reader.is_valid());
+        assert_eq!(window.get_ref().title, "a");
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot set a computed value")]
+    fn set_of_a_computed_panics() {
+        let rt = Runtime::new();
+        let mut c = rt.computed(|| 1);
+        c.set(2);
+    }
+
+    #[test]
+    fn projections_recompute_only_readers_of_changed_fields() {
+        let rt = Runtime::new();
+        let window = rt.var(Window {
+            title: "a".into(),
+            size: (1, 1),
+        });
+        let mut title = window.project(|w| &w.title, |w, title| w.title = title);
+        let mut size = window.project(|w| &w.size, |w, size| w.size = size);
+        let evaluation_count = Rc::new(Cell::new(0));
+        let title_len = {
+            let ec = evaluation_count.clone();
+            map!(|title| {
+                ec.set(ec.get() + 1);
+                title.len()
+            })
+        };
+        let area = map!(|size| size.0 * size.1);
+        assert_eq!(title_len.get(), 1);
+        assert_eq!(area.get(), 1);
+
+        size.set((2, 3));
+        assert_eq!(area.get(), 6);
+        assert_eq!(title_len.get(), 1);
+        assert_eq!(evaluation_count.get(), 1);
+
+        title.set("abc".into());
+        assert_eq!(title_len.get(), 3);
+        assert_eq!(evaluation_count.get(), 2);
+        assert_eq!(
+            window.get(),
+            Window {
+                title: "abc".into(),
+                size: (2, 3),
+            }
+        );
+    }
+
+    #[derive(Clone, Debug, PartialEq, Reactive)]
+    struct Settings<T: Clone> {
+        name: String,
+        level: T,
+    }
+
+    #[test]
+    fn derive_reactive() {
+        let rt = Runtime::new();
+        let mut settings = ReactiveSettings::new(
+            &rt,
+            Settings {
+                name: "a".into(),
+                level: 1,
+            },
+        );
+        let snapshot = settings.snapshot();
+        let evaluation_count = Rc::new(Cell::new(0));
+        let name_len = {
+            let ec = evaluation_count.clone();
+            let name = settings.name.clone();
+            map!(|name| {
+                ec.set(ec.get() + 1);
+                name.len()
+            })
+        };
+        assert_eq!(name_len.get(), 1);
+
+        settings.level.set(2);
+        assert_eq!(
+            snapshot.get(),
+            Settings {
+                name: "a".into(),
+                level: 2
+            }
+        );
+        assert_eq!(name_len.get(), 1);
+        assert_eq!(evaluation_count.get(), 1);
+
+        settings.set_all(Settings {
+            name: "abc".into(),
+            level: 3,
+        });
+        assert_eq!(settings.name.get(), "abc");
+        assert_eq!(snapshot.get().level, 3);
+        assert_eq!(name_len.get(), 3);
+    }
+
+    /// This is a syntax test. Fields may have the names of the parameters of the generated
+    /// functions.
+    #[derive(Clone, Debug, PartialEq, Reactive)]
+    struct Shadowing {
+        runtime: u32,
+        value: u32,
+    }
+
+    #[test]
+    fn derive_reactive_with_shadowing_field_names() {
+        let rt = Runtime::new();
+        let mut shadowing = ReactiveShadowing::new(
+            &rt,
+            Shadowing {
+                runtime: 1,
+                value: 2,
+            },
+        );
+        let snapshot = shadowing.snapshot();
+        shadowing.set_all(Shadowing {
+            runtime: 3,
+            value: 4,
+        });
+        assert_eq!(
+            snapshot.get(),
+            Shadowing {
+                runtime: 3,
+                value: 4
+            }
+        );
+    }
 }
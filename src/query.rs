@@ -0,0 +1,215 @@
+use crate::{query_table::LruValues, Runtime};
+use std::{any, hash::Hash};
+
+impl Runtime {
+    /// Evaluates the query `Q` for `key` and returns a clone of the result.
+    ///
+    /// The result is cached in a computed value per query and key, so that querying the same key
+    /// again recomputes it only if one of its dependencies changed. Reads of the query are
+    /// tracked like reads of the computed value.
+    ///
+    /// Results that are read by other values are always kept. If a query caches more than
+    /// `QUERY_CAPACITY` (64) results, the least recently used of the others are dropped. The caches
+    /// are dropped as soon the runtime is only referred to by its values.
+    ///
+    /// `Q` identifies the query and must always be used with the same `compute` function. This is
+    /// what `#[query]` generates.
+    pub fn query<Q, K, V>(&self, key: K, compute: fn(&Runtime, K) -> V) -> V
+    where
+        Q: 'static,
+        K: Clone + Eq + Hash + 'static,
+        V: Clone + 'static,
+    {
+        let value = self.with_query_cache::<Q, QueryCache<K, V>, _>(|cache| {
+            cache.0.get_or_insert_with(&key, || {
+                // The runtime owns the cache, so the value must not keep it alive.
+                let runtime = self.downgrade();
+                let key = key.clone();
+                // The value is owned by the cache, not by the scope it is first queried in.
+                self.unscoped(|| {
+                    self.computed(move || compute(&runtime.upgrade().unwrap(), key.clone()))
+                })
+                .named(any::type_name::<Q>())
+            })
+        });
+        value.get()
+    }
+}
+
+/// The number of results per query that are kept while no value reads them.
+const QUERY_CAPACITY: usize = 64;
+
+/// The results of a query by key.
+struct QueryCache<K, V: 'static>(LruValues<K, V>);
+
+impl<K, V> Default for QueryCache<K, V>
+where
+    K: Clone + Eq + Hash,
+{
+    fn default() -> Self {
+        QueryCache(LruValues::new(QUERY_CAPACITY))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QUERY_CAPACITY;
+    use crate::{query, Runtime, Value};
+    use std::cell::{Cell, RefCell};
+
+    thread_local! {
+        static EVALUATIONS: Cell<usize> = const { Cell::new(0) };
+        static INPUT: RefCell<Option<Value<u64>>> = const { RefCell::new(None) };
+    }
+
+    fn evaluations() -> usize {
+        EVALUATIONS.with(|evaluations| evaluations.replace(0))
+    }
+
+    #[query]
+    fn fib(rt: &Runtime, n: u64) -> u64 {
+        EVALUATIONS.with(|evaluations| evaluations.set(evaluations.get() + 1));
+        match n {
+            0 | 1 => n,
+            n => fib(rt, n - 1) + fib(rt, n - 2),
+        }
+    }
+
+    #[query]
+    fn double(_rt: &Runtime, n: u64) -> u64 {
+        EVALUATIONS.with(|evaluations| evaluations.set(evaluations.get() + 1));
+        n * 2
+    }
+
+    #[query]
+    fn scaled(_rt: &Runtime, factor: u64) -> u64 {
+        EVALUATIONS.with(|evaluations| evaluations.set(evaluations.get() + 1));
+        INPUT.with(|input| input.borrow().as_ref().unwrap().get()) * factor
+    }
+
+    #[test]
+    fn query_is_cached_per_key() {
+        let rt = Runtime::new();
+        let f = {
+            let rt = rt.clone();
+            rt.clone().computed(move || fib(&rt, 30))
+        };
+        assert_eq!(f.get(), 832040);
+        assert_eq!(evaluations(), 31);
+    }
+
+    #[test]
+    fn query_tracks_dependencies() {
+        let rt = Runtime::new();
+        let mut input = rt.var(1);
+        INPUT.with(|i| *i.borrow_mut() = Some(input.clone()));
+        let sum = {
+            let rt = rt.clone();
+            rt.clone()
+                .computed(move || scaled(&rt, 2) + scaled(&rt, 3) + scaled(&rt, 2))
+        };
+        assert_eq!(sum.get(), 7);
+        assert_eq!(evaluations(), 2);
+        assert_eq!(sum.get(), 7);
+        assert_eq!(evaluations(), 0);
+
+        input.set(2);
+        assert_eq!(sum.get(), 14);
+        assert_eq!(evaluations(), 2);
+    }
+
+    #[test]
+    fn query_results_are_not_disposed_with_a_scope() {
+        let rt = Runtime::new();
+        let mut input = rt.var(1);
+        INPUT.with(|i| *i.borrow_mut() = Some(input.clone()));
+        let (scope, ()) = rt.scope(|_| assert_eq!(scaled(&rt, 2), 2));
+        let c = {
+            let rt = rt.clone();
+            rt.clone().computed(move || scaled(&rt, 2))
+        };
+        assert_eq!(c.get(), 2);
+        drop(scope);
+
+        input.set(2);
+        assert_eq!(c.get(), 4);
+    }
+
+    #[test]
+    fn query_results_are_cached_without_readers() {
+        let rt = Runtime::new();
+        let c = {
+            let rt = rt.clone();
+            rt.clone().computed(move || fib(&rt, 2))
+        };
+        assert_eq!(c.get(), 1);
+        assert_eq!(evaluations(), 3);
+        assert_eq!(rt.nodes().len(), 4);
+
+        drop(c);
+        assert_eq!(rt.nodes().len(), 3);
+        assert_eq!(fib(&rt, 2), 1);
+        assert_eq!(fib(&rt, 3), 2);
+        assert_eq!(fib(&rt, 3), 2);
+        assert_eq!(evaluations(), 1);
+    }
+
+    #[test]
+    fn least_recently_used_unread_query_results_are_evicted() {
+        let rt = Runtime::new();
+        let reader = {
+            let rt = rt.clone();
+            rt.clone().computed(move || double(&rt, 0))
+        };
+        assert_eq!(reader.get(), 0);
+        for n in 1..=QUERY_CAPACITY as u64 + 1 {
+            assert_eq!(double(&rt, n), n * 2);
+        }
+        assert_eq!(evaluations(), QUERY_CAPACITY + 2);
+        // The result that is read is kept, the least recently used unread ones were evicted.
+        assert_eq!(rt.nodes().len(), 1 + QUERY_CAPACITY);
+
+        assert_eq!(double(&rt, 0), 0);
+        assert_eq!(
+            double(&rt, QUERY_CAPACITY as u64 + 1),
+            (QUERY_CAPACITY as u64 + 1) * 2
+        );
+        assert_eq!(evaluations(), 0);
+        assert_eq!(double(&rt, 1), 2);
+        assert_eq!(evaluations(), 1);
+    }
+
+    #[test]
+    fn query_caches_are_dropped_with_the_runtime() {
+        let rt = Runtime::new();
+        let mut input = rt.var(1);
+        INPUT.with(|i| *i.borrow_mut() = Some(input.clone()));
+        assert_eq!(fib(&rt, 10), 55);
+        assert_eq!(scaled(&rt, 2), 2);
+        input.set(2);
+        assert_eq!(scaled(&rt, 2), 4);
+        INPUT.with(|i| *i.borrow_mut() = None);
+        drop(input);
+
+        let weak = rt.downgrade();
+        drop(rt);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn queries_are_cached_per_runtime() {
+        let rt1 = Runtime::new();
+        let rt2 = Runtime::new();
+        let c1 = {
+            let rt = rt1.clone();
+            rt1.computed(move || fib(&rt, 3))
+        };
+        let c2 = {
+            let rt = rt2.clone();
+            rt2.computed(move || fib(&rt, 3))
+        };
+        assert_eq!(c1.get(), 2);
+        assert_eq!(c2.get(), 2);
+        assert_eq!(evaluations(), 8);
+    }
+}
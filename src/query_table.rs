@@ -0,0 +1,295 @@
+use crate::{runtime::RefCellNode, Runtime, Value, WeakValue};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    rc::Rc,
+};
+
+impl Runtime {
+    /// Create a table that lazily creates one computed value per key.
+    ///
+    /// The table keeps at most `capacity` values alive that are not read by other values. If it
+    /// grows beyond that, the least recently used of them are dropped. Values that are read by
+    /// other values are never dropped, so the table might hold more than `capacity` values.
+    pub fn query_table<K, V>(
+        &self,
+        capacity: usize,
+        compute: impl Fn(&K) -> V + 'static,
+    ) -> QueryTable<K, V>
+    where
+        K: Clone + Eq + Hash + 'static,
+    {
+        QueryTable(Rc::new(RefCell::new(TableInner {
+            runtime: self.clone(),
+            compute: Rc::new(compute),
+            values: LruValues::new(capacity),
+        })))
+    }
+}
+
+/// A table of computed values by key. Create it with `Runtime::query_table()`.
+///
+/// This is a cheap to clone handle, clones refer to the same table.
+pub struct QueryTable<K, V: 'static>(Rc<RefCell<TableInner<K, V>>>);
+
+impl<K, V> Clone for QueryTable<K, V> {
+    fn clone(&self) -> Self {
+        QueryTable(self.0.clone())
+    }
+}
+
+/// Statistics of a `QueryTable`.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct QueryStats {
+    /// Lookups of keys that had a value in the table.
+    pub hits: u64,
+    /// Lookups of keys that needed a new value.
+    pub misses: u64,
+    /// Values that were dropped because the table exceeded its capacity.
+    pub evictions: u64,
+}
+
+struct TableInner<K, V: 'static> {
+    runtime: Runtime,
+    compute: Rc<dyn Fn(&K) -> V>,
+    values: LruValues<K, V>,
+}
+
+/// Values by key. Keeps at most `capacity` values that are not read by other values, the least
+/// recently used of them are dropped first. Used by `QueryTable` and the caches of `#[query]`.
+pub(crate) struct LruValues<K, V: 'static> {
+    capacity: usize,
+    entries: HashMap<K, Entry<V>>,
+    /// The keys ordered by their last use.
+    lru: BTreeMap<u64, K>,
+    /// Incremented on every lookup.
+    tick: u64,
+    stats: QueryStats,
+}
+
+struct Entry<V: 'static> {
+    value: WeakValue<V>,
+    /// Keeps the value alive. The handle is not stored, because the query caches are owned by the
+    /// runtime and handles keep the runtime alive.
+    _node: Rc<dyn RefCellNode>,
+    last_used: u64,
+}
+
+impl<K, V> QueryTable<K, V>
+where
+    K: Clone + Eq + Hash + 'static,
+{
+    /// Returns the value for `key`, creates it if it's not in the table.
+    pub fn value(&self, key: &K) -> Value<V> {
+        let mut inner = self.0.borrow_mut();
+        let TableInner {
+            runtime,
+            compute,
+            values,
+        } = &mut *inner;
+        values.get_or_insert_with(key, || {
+            let compute = compute.clone();
+            let key = key.clone();
+            // The value is owned by the table, not by the scope it is first read in.
+            runtime.unscoped(|| runtime.computed(move || compute(&key)))
+        })
+    }
+
+    /// If needed, evaluates the value for `key`, then clones it and returns it.
+    ///
+    /// The read is tracked like a read of the value.
+    pub fn get(&self, key: &K) -> V
+    where
+        V: Clone,
+    {
+        self.value(key).get()
+    }
+
+    /// Returns `true` if the table holds a value for `key`. Does not count as a use of the key.
+    pub fn contains(&self, key: &K) -> bool {
+        self.0.borrow().values.entries.contains_key(key)
+    }
+
+    /// The number of values in the table.
+    pub fn len(&self) -> usize {
+        self.0.borrow().values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> QueryStats {
+        self.0.borrow().values.stats
+    }
+}
+
+impl<K, V> LruValues<K, V>
+where
+    K: Clone + Eq + Hash,
+{
+    pub fn new(capacity: usize) -> Self {
+        LruValues {
+            capacity,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            stats: QueryStats::default(),
+        }
+    }
+
+    /// Returns the value for `key`, creates it with `create` if there is none.
+    pub fn get_or_insert_with(&mut self, key: &K, create: impl FnOnce() -> Value<V>) -> Value<V> {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.stats.hits += 1;
+            self.lru.remove(&entry.last_used);
+            self.lru.insert(tick, key.clone());
+            entry.last_used = tick;
+            return entry.value.upgrade().unwrap();
+        }
+
+        self.stats.misses += 1;
+        let value = create();
+        self.entries.insert(
+            key.clone(),
+            Entry {
+                value: value.downgrade(),
+                _node: value.node(),
+                last_used: tick,
+            },
+        );
+        self.lru.insert(tick, key.clone());
+        self.evict(tick);
+        value
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Drops the least recently used values that are not read until the table is within its
+    /// capacity. The value that was used at `current` is kept.
+    fn evict(&mut self, current: u64) {
+        let excess = self.entries.len().saturating_sub(self.capacity);
+        if excess == 0 {
+            return;
+        }
+        let unread: Vec<u64> = self
+            .lru
+            .iter()
+            .filter(|&(&last_used, key)| {
+                last_used != current && !self.entries[key].value.upgrade().unwrap().is_read()
+            })
+            .map(|(&last_used, _)| last_used)
+            .take(excess)
+            .collect();
+        for last_used in unread {
+            let key = self.lru.remove(&last_used).unwrap();
+            self.entries.remove(&key);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{QueryStats, Runtime};
+    use std::{cell::Cell, rc::Rc};
+
+    #[test]
+    fn values_are_created_once_per_key() {
+        let rt = Runtime::new();
+        let count = Rc::new(Cell::new(0));
+        let table = {
+            let count = count.clone();
+            rt.query_table(10, move |key: &i32| {
+                count.set(count.get() + 1);
+                key * 2
+            })
+        };
+        assert_eq!(table.get(&1), 2);
+        assert_eq!(table.get(&2), 4);
+        assert_eq!(table.get(&1), 2);
+        assert_eq!(count.get(), 2);
+        assert_eq!(table.len(), 2);
+        assert_eq!(
+            table.stats(),
+            QueryStats {
+                hits: 1,
+                misses: 2,
+                evictions: 0
+            }
+        );
+    }
+
+    #[test]
+    fn values_track_dependencies() {
+        let rt = Runtime::new();
+        let mut factor = rt.var(2);
+        let table = {
+            let factor = factor.clone();
+            rt.query_table(10, move |key: &i32| key * factor.get())
+        };
+        let sum = {
+            let table = table.clone();
+            rt.computed(move || table.get(&1) + table.get(&2))
+        };
+        assert_eq!(sum.get(), 6);
+        factor.set(3);
+        assert_eq!(sum.get(), 9);
+        assert_eq!(table.stats().misses, 2);
+    }
+
+    #[test]
+    fn values_are_not_disposed_with_a_scope() {
+        let rt = Runtime::new();
+        let mut factor = rt.var(2);
+        let table = {
+            let factor = factor.clone();
+            rt.query_table(10, move |key: &i32| key * factor.get())
+        };
+        let (scope, ()) = rt.scope(|_| assert_eq!(table.get(&1), 2));
+        drop(scope);
+        factor.set(5);
+        assert_eq!(table.get(&1), 5);
+    }
+
+    #[test]
+    fn least_recently_used_values_are_evicted() {
+        let rt = Runtime::new();
+        let table = rt.query_table(2, |key: &i32| *key);
+        table.get(&1);
+        table.get(&2);
+        table.get(&1);
+        table.get(&3);
+        assert!(table.contains(&1));
+        assert!(!table.contains(&2));
+        assert!(table.contains(&3));
+        assert_eq!(table.stats().evictions, 1);
+    }
+
+    #[test]
+    fn values_that_are_read_are_not_evicted() {
+        let rt = Runtime::new();
+        let table = rt.query_table(1, |key: &i32| *key);
+        let reader = {
+            let table = table.clone();
+            rt.computed(move || table.get(&1))
+        };
+        assert_eq!(reader.get(), 1);
+        table.get(&2);
+        table.get(&3);
+        assert!(table.contains(&1));
+        assert!(!table.contains(&2));
+        assert!(table.contains(&3));
+        assert_eq!(table.len(), 2);
+
+        drop(reader);
+        table.get(&4);
+        assert_eq!(table.len(), 1);
+        assert!(table.contains(&4));
+    }
+}
@@ -0,0 +1,622 @@
+use crate::{stream, Consumer, ConsumerValue, Runtime, Value};
+use std::{
+    cell::{Ref, RefCell},
+    cmp::Ordering,
+    mem,
+    rc::Rc,
+};
+
+/// A change of a `ReactiveVec`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum VecDiff<T> {
+    Push(T),
+    Insert(usize, T),
+    Remove(usize),
+    Swap(usize, usize),
+    Set(usize, T),
+    /// All elements were replaced, for example because a derived vec was rebuilt.
+    Replace(Vec<T>),
+}
+
+impl<T> VecDiff<T> {
+    /// Applies the change to `vec`.
+    pub fn apply(self, vec: &mut Vec<T>) {
+        match self {
+            VecDiff::Push(value) => vec.push(value),
+            VecDiff::Insert(index, value) => vec.insert(index, value),
+            VecDiff::Remove(index) => {
+                vec.remove(index);
+            }
+            VecDiff::Swap(a, b) => vec.swap(a, b),
+            VecDiff::Set(index, value) => vec[index] = value,
+            VecDiff::Replace(values) => *vec = values,
+        }
+    }
+
+    /// Maps the elements of the change.
+    pub fn map<U>(self, mut f: impl FnMut(&T) -> U) -> VecDiff<U> {
+        match self {
+            VecDiff::Push(value) => VecDiff::Push(f(&value)),
+            VecDiff::Insert(index, value) => VecDiff::Insert(index, f(&value)),
+            VecDiff::Remove(index) => VecDiff::Remove(index),
+            VecDiff::Swap(a, b) => VecDiff::Swap(a, b),
+            VecDiff::Set(index, value) => VecDiff::Set(index, f(&value)),
+            VecDiff::Replace(values) => VecDiff::Replace(values.iter().map(f).collect()),
+        }
+    }
+}
+
+impl Runtime {
+    /// Create a reactive vec that contains `items`.
+    pub fn vec<T>(&self, items: Vec<T>) -> ReactiveVec<T> {
+        ReactiveVec(self.var(VecState {
+            items,
+            changes: Rc::new(RefCell::new(stream::producer())),
+        }))
+    }
+}
+
+/// A vec that records its changes, so that vecs derived from it with `map()`, `filter()`, and
+/// `sort_by()` are updated incrementally instead of being recomputed.
+///
+/// Reads are tracked like reads of a value. Every change invalidates the readers.
+///
+/// This is a cheap to clone handle, clones refer to the same vec. Derived vecs can't be changed,
+/// their mutating functions panic.
+pub struct ReactiveVec<T: 'static>(Value<VecState<T>>);
+
+impl<T> Clone for ReactiveVec<T> {
+    fn clone(&self) -> Self {
+        ReactiveVec(self.0.clone())
+    }
+}
+
+struct VecState<T> {
+    items: Vec<T>,
+    /// Shared with the compute function of a derived vec, so that its consumers keep receiving
+    /// changes after it was rebuilt.
+    changes: Rc<RefCell<stream::Producer<VecDiff<T>>>>,
+}
+
+impl<T: Clone> VecState<T> {
+    fn change(&mut self, diff: VecDiff<T>) {
+        self.changes.borrow_mut().produce(diff.clone());
+        diff.apply(&mut self.items);
+    }
+}
+
+impl<T> ReactiveVec<T> {
+    /// If needed, evaluates the vec and returns a reference to its elements.
+    pub fn get_ref(&self) -> Ref<'_, [T]> {
+        Ref::map(self.0.get_ref(), |state| &state.items[..])
+    }
+
+    /// If needed, evaluates the vec and returns a clone of its elements.
+    pub fn get(&self) -> Vec<T>
+    where
+        T: Clone,
+    {
+        self.get_ref().to_vec()
+    }
+
+    pub fn len(&self) -> usize {
+        self.get_ref().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Clone> ReactiveVec<T> {
+    /// Returns a consumer that receives all changes from now on.
+    pub fn changes(&self) -> Consumer<VecDiff<T>> {
+        let mut consumer = Some(ConsumerValue::new(
+            self.0.peek().changes.borrow().subscribe(),
+        ));
+        let vec = self.0.clone();
+        self.0.runtime().computed_with_prev(move |prev| {
+            let state = vec.get_ref();
+            // Like `Producer::subscribe()`, consumption continues from now on if the consumer got
+            // lost.
+            prev.or_else(|| consumer.take())
+                .unwrap_or_else(|| ConsumerValue::new(state.changes.borrow().subscribe()))
+        })
+    }
+
+    #[track_caller]
+    pub fn push(&mut self, value: T) {
+        self.0.apply(|mut state| {
+            state.change(VecDiff::Push(value));
+            state
+        })
+    }
+
+    /// Panics if `index > len`.
+    #[track_caller]
+    pub fn insert(&mut self, index: usize, value: T) {
+        let len = self.0.peek().items.len();
+        assert!(
+            index <= len,
+            "insertion index (is {index}) should be <= len (is {len})"
+        );
+        self.0.apply(|mut state| {
+            state.change(VecDiff::Insert(index, value));
+            state
+        })
+    }
+
+    /// Removes and returns the element at `index`.
+    ///
+    /// Panics if `index` is out of bounds.
+    #[track_caller]
+    pub fn remove(&mut self, index: usize) -> T {
+        let len = self.0.peek().items.len();
+        assert!(
+            index < len,
+            "removal index (is {index}) should be < len (is {len})"
+        );
+        let mut removed = None;
+        self.0.apply(|mut state| {
+            state.changes.borrow_mut().produce(VecDiff::Remove(index));
+            removed = Some(state.items.remove(index));
+            state
+        });
+        removed.unwrap()
+    }
+
+    /// Panics if `a` or `b` are out of bounds.
+    #[track_caller]
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.check_index(a);
+        self.check_index(b);
+        self.0.apply(|mut state| {
+            state.change(VecDiff::Swap(a, b));
+            state
+        })
+    }
+
+    /// Replaces the element at `index`.
+    ///
+    /// Panics if `index` is out of bounds.
+    #[track_caller]
+    pub fn set(&mut self, index: usize, value: T) {
+        self.check_index(index);
+        self.0.apply(|mut state| {
+            state.change(VecDiff::Set(index, value));
+            state
+        })
+    }
+
+    // Checked before the value is changed, because a panic while it is changed aborts.
+    fn check_index(&self, index: usize) {
+        let len = self.0.peek().items.len();
+        assert!(
+            index < len,
+            "index out of bounds: the len is {len} but the index is {index}"
+        );
+    }
+
+    /// Create a vec that contains the results of `f` for every element.
+    ///
+    /// `f` is only invoked for the elements that were added or replaced. Values read by `f` are
+    /// not tracked.
+    pub fn map<U: Clone>(&self, f: impl FnMut(&T) -> U + 'static) -> ReactiveVec<U> {
+        self.derive(Map(f))
+    }
+
+    /// Create a vec that contains the elements for which `f` returns `true`.
+    ///
+    /// `f` is only invoked for the elements that were added or replaced. Values read by `f` are
+    /// not tracked.
+    pub fn filter(&self, f: impl FnMut(&T) -> bool + 'static) -> ReactiveVec<T> {
+        self.derive(Filter {
+            f,
+            included: Vec::new(),
+        })
+    }
+
+    /// Create a vec that contains the elements sorted by `compare`. The sort is stable.
+    ///
+    /// Only the elements that were added or replaced are compared to find their position. Values
+    /// read by `compare` are not tracked.
+    pub fn sort_by(&self, compare: impl Fn(&T, &T) -> Ordering + 'static) -> ReactiveVec<T> {
+        self.derive(SortBy {
+            compare,
+            order: Vec::new(),
+        })
+    }
+
+    fn derive<O>(&self, mut operator: O) -> ReactiveVec<O::Output>
+    where
+        O: Operator<T> + 'static,
+    {
+        let source = self.0.clone();
+        let runtime = self.0.runtime();
+        let changes = Rc::new(RefCell::new(stream::producer()));
+        let mut consumer: Option<stream::Consumer<VecDiff<T>>> = None;
+        let value = self.0.runtime().computed_with_prev(move |prev| {
+            let source = source.get_ref();
+            if let (Some(mut state), Some(consumer)) = (prev, consumer.as_mut()) {
+                runtime.untrack(|| {
+                    for diff in consumer.drain() {
+                        operator.apply(diff, &mut state);
+                    }
+                });
+                return state;
+            }
+
+            // This is the first evaluation, or the previous one failed and the changes that were
+            // consumed since then are lost. Start over from the current elements.
+            let rebuild = consumer.is_some();
+            consumer = Some(source.changes.borrow().subscribe());
+            let items = runtime.untrack(|| operator.init(&source.items));
+            let mut state = VecState {
+                items: Vec::new(),
+                changes: changes.clone(),
+            };
+            if rebuild {
+                state.change(VecDiff::Replace(items));
+            } else {
+                state.items = items;
+            }
+            state
+        });
+        ReactiveVec(value)
+    }
+}
+
+/// Derives the elements of a vec from the elements of another vec.
+trait Operator<T> {
+    type Output: Clone;
+
+    /// Starts over and returns the elements derived from `items`.
+    fn init(&mut self, items: &[T]) -> Vec<Self::Output>;
+
+    /// Changes the derived elements in `target` according to a change of the source.
+    fn apply(&mut self, diff: VecDiff<T>, target: &mut VecState<Self::Output>);
+}
+
+struct Map<F>(F);
+
+impl<T, U, F> Operator<T> for Map<F>
+where
+    U: Clone,
+    F: FnMut(&T) -> U,
+{
+    type Output = U;
+
+    fn init(&mut self, items: &[T]) -> Vec<U> {
+        items.iter().map(&mut self.0).collect()
+    }
+
+    fn apply(&mut self, diff: VecDiff<T>, target: &mut VecState<U>) {
+        target.change(diff.map(&mut self.0));
+    }
+}
+
+struct Filter<F> {
+    f: F,
+    /// For every element of the source, `true` if it is included.
+    included: Vec<bool>,
+}
+
+impl<F> Filter<F> {
+    /// The position in the target of the source element at `index`.
+    fn position(&self, index: usize) -> usize {
+        self.included[..index].iter().filter(|&&i| i).count()
+    }
+}
+
+impl<T, F> Operator<T> for Filter<F>
+where
+    T: Clone,
+    F: FnMut(&T) -> bool,
+{
+    type Output = T;
+
+    fn init(&mut self, items: &[T]) -> Vec<T> {
+        self.included = items.iter().map(&mut self.f).collect();
+        items
+            .iter()
+            .zip(&self.included)
+            .filter(|(_, &included)| included)
+            .map(|(item, _)| item.clone())
+            .collect()
+    }
+
+    fn apply(&mut self, diff: VecDiff<T>, target: &mut VecState<T>) {
+        match diff {
+            VecDiff::Push(value) => {
+                let included = (self.f)(&value);
+                self.included.push(included);
+                if included {
+                    target.change(VecDiff::Push(value));
+                }
+            }
+            VecDiff::Insert(index, value) => {
+                let included = (self.f)(&value);
+                let position = self.position(index);
+                self.included.insert(index, included);
+                if included {
+                    target.change(VecDiff::Insert(position, value));
+                }
+            }
+            VecDiff::Remove(index) => {
+                let position = self.position(index);
+                if self.included.remove(index) {
+                    target.change(VecDiff::Remove(position));
+                }
+            }
+            VecDiff::Swap(a, b) => {
+                let (a, b) = (a.min(b), a.max(b));
+                match (self.included[a], self.included[b]) {
+                    (true, true) if a != b => {
+                        target.change(VecDiff::Swap(self.position(a), self.position(b)))
+                    }
+                    (true, false) | (false, true) => {
+                        // The included element moves to the other index.
+                        let (from, to) = match self.included[a] {
+                            true => (a, b),
+                            false => (b, a),
+                        };
+                        let position = self.position(from);
+                        let value = target.items[position].clone();
+                        self.included.swap(a, b);
+                        target.change(VecDiff::Remove(position));
+                        target.change(VecDiff::Insert(self.position(to), value));
+                    }
+                    _ => {}
+                }
+            }
+            VecDiff::Set(index, value) => {
+                let included = (self.f)(&value);
+                let position = self.position(index);
+                let was_included = mem::replace(&mut self.included[index], included);
+                match (was_included, included) {
+                    (true, true) => target.change(VecDiff::Set(position, value)),
+                    (true, false) => target.change(VecDiff::Remove(position)),
+                    (false, true) => target.change(VecDiff::Insert(position, value)),
+                    (false, false) => {}
+                }
+            }
+            VecDiff::Replace(items) => {
+                let items = self.init(&items);
+                target.change(VecDiff::Replace(items));
+            }
+        }
+    }
+}
+
+struct SortBy<F> {
+    compare: F,
+    /// For every element of the target, the index of the element in the source.
+    order: Vec<usize>,
+}
+
+impl<F> SortBy<F> {
+    /// The position in the target of the source element at `index`.
+    fn position(&self, index: usize) -> usize {
+        self.order.iter().position(|&i| i == index).unwrap()
+    }
+
+    /// Inserts the source element `value` at `index` into `target`. The indices of the other
+    /// elements must already be adjusted.
+    fn insert<T: Clone>(&mut self, index: usize, value: T, target: &mut VecState<T>)
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        let position = self.insert_position(index, &value, &target.items, None);
+        self.order.insert(position, index);
+        target.change(VecDiff::Insert(position, value));
+    }
+
+    /// Finds the position of the source element `value` at `index` in `items`, ignoring the
+    /// element at position `skip`.
+    fn insert_position<T>(&self, index: usize, value: &T, items: &[T], skip: Option<usize>) -> usize
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        let is_before = |position: usize| {
+            let position = match skip {
+                Some(skip) if position >= skip => position + 1,
+                _ => position,
+            };
+            (self.compare)(&items[position], value).then(self.order[position].cmp(&index))
+                == Ordering::Less
+        };
+        // Binary search for the first position that is not before `value`.
+        let (mut low, mut high) = (0, items.len() - usize::from(skip.is_some()));
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if is_before(mid) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
+    fn set<T: Clone>(&mut self, index: usize, value: T, target: &mut VecState<T>)
+    where
+        F: Fn(&T, &T) -> Ordering,
+    {
+        let position = self.position(index);
+        let new_position = self.insert_position(index, &value, &target.items, Some(position));
+        if new_position == position {
+            target.change(VecDiff::Set(position, value));
+        } else {
+            self.order.remove(position);
+            self.order.insert(new_position, index);
+            target.change(VecDiff::Remove(position));
+            target.change(VecDiff::Insert(new_position, value));
+        }
+    }
+}
+
+impl<T, F> Operator<T> for SortBy<F>
+where
+    T: Clone,
+    F: Fn(&T, &T) -> Ordering,
+{
+    type Output = T;
+
+    fn init(&mut self, items: &[T]) -> Vec<T> {
+        self.order = (0..items.len()).collect();
+        self.order
+            .sort_by(|&a, &b| (self.compare)(&items[a], &items[b]));
+        self.order.iter().map(|&i| items[i].clone()).collect()
+    }
+
+    fn apply(&mut self, diff: VecDiff<T>, target: &mut VecState<T>) {
+        match diff {
+            VecDiff::Push(value) => self.insert(self.order.len(), value, target),
+            VecDiff::Insert(index, value) => {
+                for i in &mut self.order {
+                    if *i >= index {
+                        *i += 1;
+                    }
+                }
+                self.insert(index, value, target);
+            }
+            VecDiff::Remove(index) => {
+                let position = self.position(index);
+                self.order.remove(position);
+                for i in &mut self.order {
+                    if *i > index {
+                        *i -= 1;
+                    }
+                }
+                target.change(VecDiff::Remove(position));
+            }
+            VecDiff::Swap(a, b) => {
+                let value_a = target.items[self.position(a)].clone();
+                let value_b = target.items[self.position(b)].clone();
+                self.set(a, value_b, target);
+                self.set(b, value_a, target);
+            }
+            VecDiff::Set(index, value) => self.set(index, value, target),
+            VecDiff::Replace(items) => {
+                let items = self.init(&items);
+                target.change(VecDiff::Replace(items));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Runtime, VecDiff};
+    use std::{cell::Cell, rc::Rc};
+
+    #[test]
+    fn changes_are_recorded() {
+        let rt = Runtime::new();
+        let mut vec = rt.vec(vec![1, 2]);
+        let changes = vec.changes();
+        vec.push(3);
+        vec.insert(0, 0);
+        assert_eq!(vec.remove(1), 1);
+        vec.swap(0, 2);
+        vec.set(1, 4);
+        assert_eq!(vec.get(), [3, 4, 0]);
+        assert_eq!(
+            changes.get_ref().drain().collect::<Vec<_>>(),
+            [
+                VecDiff::Push(3),
+                VecDiff::Insert(0, 0),
+                VecDiff::Remove(1),
+                VecDiff::Swap(0, 2),
+                VecDiff::Set(1, 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn map_is_invoked_for_changed_elements_only() {
+        let rt = Runtime::new();
+        let mut vec = rt.vec(vec![1, 2, 3]);
+        let invocations = Rc::new(Cell::new(0));
+        let doubled = {
+            let invocations = invocations.clone();
+            vec.map(move |x| {
+                invocations.set(invocations.get() + 1);
+                x * 2
+            })
+        };
+        assert_eq!(doubled.get(), [2, 4, 6]);
+        assert_eq!(invocations.replace(0), 3);
+
+        vec.push(4);
+        vec.remove(0);
+        vec.set(0, 5);
+        assert_eq!(doubled.get(), [10, 6, 8]);
+        assert_eq!(invocations.get(), 2);
+    }
+
+    #[test]
+    fn derived_vecs_follow_their_source() {
+        let rt = Runtime::new();
+        let mut vec = rt.vec(Vec::new());
+        let even = vec.filter(|x| x % 2 == 0);
+        let sorted = vec.sort_by(|a: &u32, b| (a % 10).cmp(&(b % 10)));
+        let sorted_odd = vec
+            .map(|x| x + 1)
+            .filter(|x| x % 2 == 1)
+            .sort_by(|a, b| b.cmp(a));
+
+        // A simple linear congruential generator, so that the operations are reproducible.
+        let mut seed = 1u32;
+        let mut random = move |n: usize| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as usize % n
+        };
+        for _ in 0..500 {
+            let len = vec.len();
+            let value = random(100) as u32;
+            match random(5) {
+                _ if len == 0 => vec.push(value),
+                0 => vec.push(value),
+                1 => vec.insert(random(len + 1), value),
+                2 => {
+                    vec.remove(random(len));
+                }
+                3 => vec.swap(random(len), random(len)),
+                _ => vec.set(random(len), value),
+            }
+
+            let items = vec.get();
+            let expected: Vec<_> = items.iter().copied().filter(|x| x % 2 == 0).collect();
+            assert_eq!(even.get(), expected);
+            let mut expected = items.clone();
+            expected.sort_by_key(|x| x % 10);
+            assert_eq!(sorted.get(), expected);
+            let mut expected: Vec<_> = items.iter().map(|x| x + 1).filter(|x| x % 2 == 1).collect();
+            expected.sort_by(|a, b| b.cmp(a));
+            assert_eq!(sorted_odd.get(), expected);
+        }
+    }
+
+    #[test]
+    fn derived_vecs_are_rebuilt_after_a_panic() {
+        let rt = Runtime::new();
+        let mut vec = rt.vec(vec![1, 2]);
+        let checked = vec.map(|&x| {
+            assert!(x != 0, "zero");
+            x
+        });
+        let sorted = checked.sort_by(|a, b| b.cmp(a));
+        let changes = checked.changes();
+        assert_eq!(sorted.get(), [2, 1]);
+
+        vec.push(0);
+        assert!(checked.0.try_get_ref().is_err());
+        vec.set(2, 3);
+        assert_eq!(sorted.get(), [3, 2, 1]);
+        assert_eq!(
+            changes.get_ref().drain().collect::<Vec<_>>(),
+            [VecDiff::Replace(vec![1, 2, 3])]
+        );
+    }
+}
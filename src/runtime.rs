@@ -1,9 +1,18 @@
-use crate::value::Value;
+use crate::{
+    graph::{Graph, GraphNode, NodeKey},
+    scope::ScopeInner,
+    value::Value,
+    CycleError, Error, InvalidationCause,
+};
 use std::{
-    cell::{Cell, RefCell, RefMut},
-    collections::HashSet,
-    hash, ptr,
-    rc::Rc,
+    any::{Any, TypeId},
+    cell::{Cell, Ref, RefCell, RefMut},
+    collections::HashMap,
+    fmt, mem,
+    panic::{self, AssertUnwindSafe},
+    rc::{Rc, Weak},
+    sync::Arc,
+    thread,
 };
 
 #[derive(Clone)]
@@ -17,14 +26,93 @@ impl Runtime {
         Runtime(Rc::new(RuntimeInner::default()))
     }
 
+    pub(crate) fn downgrade(&self) -> WeakRuntime {
+        WeakRuntime(Rc::downgrade(&self.0))
+    }
+
+    /// Returns `true` if both runtimes are the same.
+    pub fn ptr_eq(&self, other: &Runtime) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
     pub fn var<T>(&self, value: T) -> Value<T> {
         Value::new_var(self, value)
     }
 
+    /// Create a var with a name that is shown in diagnostics.
+    pub fn var_named<T>(&self, name: impl Into<Arc<str>>, value: T) -> Value<T> {
+        Value::new_var(self, value).named(name)
+    }
+
     pub fn computed<T>(&self, compute: impl FnMut() -> T + 'static) -> Value<T> {
         Value::new_computed(self, compute)
     }
 
+    /// Create a computed value that may fail.
+    ///
+    /// An error is cached like a value and returned from `Value::try_get()` until one of the
+    /// dependencies changes. Inside `compute`, errors of dependencies can be propagated by using
+    /// `Value::try_get()` and `?`.
+    pub fn try_computed<T>(&self, compute: impl FnMut() -> Result<T, Error> + 'static) -> Value<T> {
+        Value::new_try_computed(self, compute)
+    }
+
+    /// Create a var that does not invalidate its readers when it is set to a value that is equal
+    /// to the current one.
+    pub fn var_eq<T>(&self, value: T) -> Value<T>
+    where
+        T: PartialEq,
+    {
+        Value::new_var(self, value).with_eq()
+    }
+
+    /// Create a computed value that compares a recomputed result to the previous one.
+    ///
+    /// If they are equal, readers of this value are considered to be up to date and are not
+    /// recomputed, unless some of their other dependencies changed.
+    pub fn computed_eq<T>(&self, compute: impl FnMut() -> T + 'static) -> Value<T>
+    where
+        T: PartialEq,
+    {
+        Value::new_computed(self, compute).with_eq()
+    }
+
+    /// Create a computed value that returns `fallback` when it is read while it is evaluating,
+    /// instead of failing with a `CycleError`.
+    ///
+    /// Note that only `Value::get()` and `Value::try_get()` return the fallback value.
+    pub fn computed_with_cycle_fallback<T>(
+        &self,
+        compute: impl FnMut() -> T + 'static,
+        fallback: T,
+    ) -> Value<T>
+    where
+        T: Clone,
+    {
+        Value::new_computed(self, compute).with_cycle_fallback(fallback)
+    }
+
+    /// Create a computed value that receives its previous value when it is recomputed.
+    ///
+    /// The previous value is `None` in the first evaluation and after the value was taken or its
+    /// evaluation failed. Because `compute` consumes the previous value, a recomputation is always
+    /// considered a change.
+    pub fn computed_with_prev<T>(&self, compute: impl FnMut(Option<T>) -> T + 'static) -> Value<T> {
+        Value::new_computed_with_prev(self, compute)
+    }
+
+    /// Create a computed value that accumulates its results.
+    ///
+    /// Starting with `init`, `f` is called with the accumulated value whenever one of the
+    /// dependencies it read changes, and returns the new accumulated value. For example
+    /// `rt.fold(0, move |sum| sum + a.get())` sums up all values `a` had when it was read.
+    pub fn fold<T>(&self, init: T, mut f: impl FnMut(T) -> T + 'static) -> Value<T>
+    where
+        T: Clone,
+    {
+        self.computed_with_prev(move |acc| f(acc.unwrap_or_else(|| init.clone())))
+    }
+
     /// Create a computed value that memoizes its result.
     ///
     /// The `key` function is invoked to determine if the value should be recomputed. If the key
@@ -66,115 +154,557 @@ impl Runtime {
         })
     }
 
-    pub(crate) fn eval(&self, current: NodePtr, f: impl FnOnce()) {
+    /// Enables or disables the recording of invalidation causes.
+    ///
+    /// While enabled, changes to vars remember their call site, and every computed value that gets
+    /// invalidated records the path from the changed var to itself. The cause is available with
+    /// `Value::last_invalidation_cause()` and, with the `log` feature, logged at the debug level.
+    ///
+    /// Recording allocates for every invalidated node, so this is meant for debugging only.
+    pub fn trace_invalidations(&self, enabled: bool) {
+        self.0.trace_invalidations.set(enabled);
+    }
+
+    pub(crate) fn traces_invalidations(&self) -> bool {
+        self.0.trace_invalidations.get()
+    }
+
+    /// Run `f` as a batch.
+    ///
+    /// Vars that are changed inside the batch are updated immediately, but the invalidation of
+    /// their readers is postponed until the outermost batch commits. This way every affected
+    /// reader is invalidated only once, even if multiple of its dependencies change.
+    ///
+    /// Reads inside the batch see the new values of the vars, but computed values that were valid
+    /// before the batch was started keep returning their previous values until the batch commits.
+    ///
+    /// Batches can be nested, only the outermost batch commits. If `f` panics, all changes that
+    /// were made up to the panic are committed while unwinding.
+    pub fn batch<R>(&self, f: impl FnOnce() -> R) -> R {
+        struct Batch<'a>(&'a RuntimeInner);
+
+        impl Drop for Batch<'_> {
+            fn drop(&mut self) {
+                let depth = self.0.batch_depth.get() - 1;
+                self.0.batch_depth.set(depth);
+                if depth == 0 {
+                    self.0.commit();
+                }
+            }
+        }
+
         let inner = &*self.0;
-        let prev = inner.current.get();
-        inner.current.set(Some(current));
-        f();
-        inner.current.set(prev);
+        inner.batch_depth.set(inner.batch_depth.get() + 1);
+        let _batch = Batch(inner);
+        f()
+    }
+
+    /// Re-runs all effects that were invalidated, until there are no invalidated effects left.
+    ///
+    /// Effects are verified before they run, so that they are only re-run if one of their
+    /// dependencies actually changed.
+    ///
+    /// This is done automatically when an outermost batch commits.
+    ///
+    /// If effects panic, all other effects are run, and then the first panic is re-raised.
+    pub fn flush_effects(&self) {
+        self.0.flush_effects();
     }
 
-    pub(crate) fn current(&self) -> Option<NodePtr> {
-        self.0.current.get()
+    /// Returns `true` if there is a batch active.
+    pub(crate) fn is_batching(&self) -> bool {
+        self.0.batch_depth.get() > 0
     }
-}
 
-#[derive(Default)]
-struct RuntimeInner {
-    /// The currently evaluating value.
-    current: Cell<Option<NodePtr>>,
+    /// Postpones the invalidation of `node` until the current batch commits.
+    pub(crate) fn defer_invalidation(&self, key: NodeKey, node: Rc<dyn RefCellNode>) {
+        debug_assert!(self.is_batching());
+        self.0.pending.borrow_mut().insert(key, node);
+    }
+
+    /// Marks the readers of `key` as outdated and schedules the effects among them.
+    pub(crate) fn invalidate(&self, key: NodeKey, cause: Option<&mut InvalidationCause>) {
+        let effects = self.0.graph.borrow_mut().invalidate(key, cause);
+        self.0.dirty_effects.borrow_mut().extend(effects);
+    }
+
+    /// Evaluates `f` with `frame` on top of the evaluation stack and returns its result together
+    /// with the frame, which contains the dependencies it read.
+    ///
+    /// If `f` panics, the panic is returned together with the dependencies read up to the panic.
+    pub(crate) fn eval<R>(
+        &self,
+        frame: Frame,
+        f: impl FnOnce() -> R,
+    ) -> (thread::Result<R>, Frame) {
+        self.0.stack.borrow_mut().push(frame);
+        // Values created while evaluating belong to this runtime.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.with_default(f)));
+        let frame = self.0.stack.borrow_mut().pop().unwrap();
+        (result, frame)
+    }
+
+    /// Verifies the dependencies of a node with `verify` while the node is on the evaluation
+    /// stack, so that dependencies that read the node again are detected as a cycle.
+    ///
+    /// Reads while verifying are not tracked.
+    pub(crate) fn verify<R>(&self, mut frame: Frame, verify: impl FnOnce() -> R) -> R {
+        // Pops the frame even if `verify` panics.
+        struct Pop<'a>(&'a RuntimeInner);
+        impl Drop for Pop<'_> {
+            fn drop(&mut self) {
+                self.0.stack.borrow_mut().pop();
+            }
+        }
+
+        frame.tracking = false;
+        self.0.stack.borrow_mut().push(frame);
+        let _pop = Pop(&self.0);
+        verify()
+    }
+
+    /// The number of nodes that are evaluating.
+    pub(crate) fn depth(&self) -> usize {
+        self.0.stack.borrow().len()
+    }
+
+    /// The currently evaluating node.
+    pub(crate) fn current(&self) -> Option<NodeKey> {
+        self.0.stack.borrow().last().map(|frame| frame.node)
+    }
+
+    /// Records a read of `node` by the currently evaluating node, if it tracks its reads.
+    pub(crate) fn track_read(&self, key: NodeKey, node: Rc<dyn RefCellNode>) {
+        let mut stack = self.0.stack.borrow_mut();
+        let Some(frame) = stack.last_mut().filter(|frame| frame.tracking) else {
+            return;
+        };
+        if self.0.graph.borrow_mut().add_edge(frame.node, key) {
+            frame.dependencies.push(node);
+        }
+    }
+
+    /// Records that the currently evaluating node read an outdated value that could not be updated,
+    /// because it was borrowed.
+    pub(crate) fn track_stale_read(&self) {
+        if let Some(frame) = self.0.stack.borrow_mut().last_mut() {
+            frame.stale = true;
+        }
+    }
+
+    /// Runs `f` without tracking the values it reads as dependencies of the value that is
+    /// currently evaluating.
+    ///
+    /// Values that are evaluated inside `f` still track their own dependencies.
+    pub fn untrack<R>(&self, f: impl FnOnce() -> R) -> R {
+        // Restores the tracking state even if `f` panics.
+        struct Restore<'a>(&'a RuntimeInner, bool);
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                if let Some(frame) = self.0.stack.borrow_mut().last_mut() {
+                    frame.tracking = self.1;
+                }
+            }
+        }
+
+        let tracking = self
+            .0
+            .stack
+            .borrow_mut()
+            .last_mut()
+            .map(|frame| mem::replace(&mut frame.tracking, false));
+        let Some(tracking) = tracking else {
+            return f();
+        };
+        let _restore = Restore(&self.0, tracking);
+        f()
+    }
+
+    /// If `node` is evaluating, returns the cycle that is created by reading from it.
+    pub(crate) fn detect_cycle(&self, node: NodeKey) -> Option<CycleError> {
+        let stack = self.0.stack.borrow();
+        let start = stack.iter().position(|frame| frame.node == node)?;
+        let graph = self.graph();
+        let nodes = stack[start..]
+            .iter()
+            .map(|frame| graph.node(frame.node).label.clone())
+            .collect();
+        Some(CycleError::new(nodes))
+    }
+
+    /// The cycle fallback value of `node` if it is evaluating.
+    pub(crate) fn cycle_fallback(&self, node: NodeKey) -> Option<Rc<dyn Any>> {
+        let stack = self.0.stack.borrow();
+        let frame = stack.iter().find(|frame| frame.node == node)?;
+        frame.cycle_fallback.clone()
+    }
+
+    /// Adds a node to the graph.
+    pub(crate) fn insert_node(&self, kind: NodeKind, value: Weak<dyn RefCellNode>) -> NodeKey {
+        let label = NodeLabel {
+            id: self.new_node_id(),
+            name: None,
+        };
+        self.0
+            .graph
+            .borrow_mut()
+            .insert(GraphNode::new(label, kind, value))
+    }
+
+    /// Removes a node that is dropped from the graph.
+    pub(crate) fn remove_node(&self, key: NodeKey) {
+        self.0.graph.borrow_mut().remove(key);
+    }
+
+    /// The scopes that are entered. The last one is the innermost.
+    pub(crate) fn scopes(&self) -> &RefCell<Vec<Rc<ScopeInner>>> {
+        &self.0.scopes
+    }
+
+    pub(crate) fn graph(&self) -> Ref<'_, Graph> {
+        self.0.graph.borrow()
+    }
+
+    pub(crate) fn graph_mut(&self) -> RefMut<'_, Graph> {
+        self.0.graph.borrow_mut()
+    }
+
+    /// Runs `f` with the cache of the query `Q`. The cache is created on first use.
+    pub(crate) fn with_query_cache<Q: 'static, C: Default + 'static, R>(
+        &self,
+        f: impl FnOnce(&mut C) -> R,
+    ) -> R {
+        let mut queries = self.0.queries.borrow_mut();
+        let cache = queries
+            .entry(TypeId::of::<Q>())
+            .or_insert_with(|| Box::<C>::default());
+        f(cache.downcast_mut().unwrap())
+    }
+
+    /// All nodes that are alive, ordered by their id.
+    pub(crate) fn nodes(&self) -> Vec<Rc<dyn RefCellNode>> {
+        let mut nodes: Vec<_> = self
+            .graph()
+            .nodes()
+            .map(|node| (node.label.id, node.value.clone()))
+            .collect();
+        nodes.sort_by_key(|(id, _)| *id);
+        nodes
+            .into_iter()
+            .filter_map(|(_, node)| node.upgrade())
+            .collect()
+    }
+
+    fn new_node_id(&self) -> NodeId {
+        let id = self.0.next_node_id.get();
+        self.0.next_node_id.set(id + 1);
+        NodeId(id)
+    }
+
+    /// The current revision.
+    pub(crate) fn revision(&self) -> Revision {
+        self.0.revision.get()
+    }
+
+    /// Starts a new revision and returns it.
+    pub(crate) fn new_revision(&self) -> Revision {
+        self.0.new_revision()
+    }
 }
 
-pub trait Node {
-    fn invalidate(&mut self);
-    fn track_read_from(&mut self, from: Rc<dyn RefCellNode>);
-    fn remove_reader(&mut self, reader: NodePtr);
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        // Values keep their runtime alive, and the query caches keep their values alive. If the
+        // runtime is only referred to by the values, i.e. every other handle was dropped, the
+        // caches can't be queried anymore and are dropped to break the cycle.
+        let Ok(queries) = self.0.queries.try_borrow() else {
+            return;
+        };
+        if queries.is_empty() {
+            return;
+        }
+        drop(queries);
+        let Ok(graph) = self.0.graph.try_borrow() else {
+            return;
+        };
+        // Every node holds one handle, this handle is dropped.
+        let unreferenced = Rc::strong_count(&self.0) - 1 <= graph.len();
+        drop(graph);
+        if unreferenced {
+            let queries = self.0.queries.take();
+            drop(queries);
+        }
+    }
 }
 
-pub trait RefCellNode {
-    fn as_ptr(&self) -> NodePtr;
+/// A handle to a runtime that does not keep it alive.
+#[derive(Clone)]
+pub(crate) struct WeakRuntime(Weak<RuntimeInner>);
 
-    fn borrow_mut(&self) -> RefMut<dyn Node>;
+impl WeakRuntime {
+    pub fn upgrade(&self) -> Option<Runtime> {
+        self.0.upgrade().map(Runtime)
+    }
+}
 
-    #[allow(clippy::mut_from_ref)]
-    unsafe fn as_mut(&self) -> &mut dyn Node;
+#[derive(Default)]
+struct RuntimeInner {
+    /// The evaluation stack. The last frame is the currently evaluating value.
+    stack: RefCell<Vec<Frame>>,
+    next_node_id: Cell<u64>,
+    /// The current revision. Incremented every time vars are changed.
+    revision: Cell<Revision>,
+    /// The nesting level of `batch()` invocations.
+    batch_depth: Cell<usize>,
+    /// Nodes that were changed inside the current batch and need to be invalidated when it commits.
+    pending: RefCell<HashMap<NodeKey, Rc<dyn RefCellNode>>>,
+    /// Effects that were invalidated and need to be re-run. Effects that were dropped in the
+    /// meantime can't be upgraded anymore and are skipped.
+    dirty_effects: RefCell<Vec<Weak<dyn RefCellNode>>>,
+    /// The dependency graph of all nodes of this runtime.
+    graph: RefCell<Graph>,
+    /// Set if invalidation causes are recorded.
+    trace_invalidations: Cell<bool>,
+    /// The scopes that are entered. See `Runtime::scope()`.
+    scopes: RefCell<Vec<Rc<ScopeInner>>>,
+    /// The caches of the queries, by the type that identifies the query. See `Runtime::query()`.
+    queries: RefCell<HashMap<TypeId, Box<dyn Any>>>,
 }
 
-impl<T> RefCellNode for RefCell<T>
-where
-    T: Node,
-{
-    fn as_ptr(&self) -> NodePtr {
-        NodePtr::new(unsafe { &*RefCell::as_ptr(self) })
+impl RuntimeInner {
+    fn commit(&self) {
+        let pending = mem::take(&mut *self.pending.borrow_mut());
+        if pending.is_empty() {
+            return;
+        }
+        // All changes of a batch share the same revision.
+        let revision = self.new_revision();
+        for node in pending.into_values() {
+            node.borrow_mut().changed(revision);
+        }
+
+        // Don't run user code while unwinding from a panic inside the batch. The effects stay
+        // scheduled until the next flush.
+        if !thread::panicking() {
+            self.flush_effects();
+        }
     }
 
-    fn borrow_mut(&self) -> RefMut<dyn Node> {
-        RefMut::map(self.borrow_mut(), |t| t as &mut dyn Node)
+    fn flush_effects(&self) {
+        let mut first_error = None;
+        loop {
+            let dirty = mem::take(&mut *self.dirty_effects.borrow_mut());
+            if dirty.is_empty() {
+                break;
+            }
+            for effect in dirty.iter().filter_map(Weak::upgrade) {
+                if let Err(e) = effect.borrow_mut().update() {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = first_error {
+            panic!("{e}");
+        }
     }
 
-    unsafe fn as_mut(&self) -> &mut dyn Node {
-        (&mut *RefCell::as_ptr(self)) as &mut dyn Node
+    fn new_revision(&self) -> Revision {
+        let revision = self.revision.get() + 1;
+        self.revision.set(revision);
+        revision
     }
 }
 
-#[derive(Clone)]
-pub(crate) struct RefCellNodeHandle(pub Rc<dyn RefCellNode>);
+/// Identifies a node in a runtime.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct NodeId(pub(crate) u64);
+
+impl NodeId {
+    /// The index of the node in the order of creation.
+    pub fn index(&self) -> u64 {
+        self.0
+    }
+}
 
-impl PartialEq for RefCellNodeHandle {
-    fn eq(&self, other: &Self) -> bool {
-        // Can't compare trait ptrs using Rc::ptr_eq.
-        let ptr_self = self.0.as_ptr();
-        let ptr_other = other.0.as_ptr();
-        ptr_self == ptr_other
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
     }
 }
 
-impl Eq for RefCellNodeHandle {}
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum NodeKind {
+    Var,
+    Computed,
+    Effect,
+}
 
-impl hash::Hash for RefCellNodeHandle {
-    fn hash<H: hash::Hasher>(&self, state: &mut H) {
-        Rc::as_ptr(&self.0).hash(state)
+impl fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NodeKind::Var => "var",
+            NodeKind::Computed => "computed",
+            NodeKind::Effect => "effect",
+        })
     }
 }
 
-impl RefCellNodeHandle {
-    #[allow(clippy::mut_from_ref)]
-    pub unsafe fn as_mut(&self) -> &mut dyn Node {
-        self.0.as_mut()
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub(crate) enum NodeState {
+    /// The value is up to date.
+    Valid,
+    /// The value needs to be verified before it can be used.
+    Outdated,
+    /// The value was never evaluated.
+    Unevaluated,
+    /// The evaluation failed or panicked.
+    Failed,
+}
+
+impl fmt::Display for NodeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NodeState::Valid => "valid",
+            NodeState::Outdated => "outdated",
+            NodeState::Unevaluated => "unevaluated",
+            NodeState::Failed => "failed",
+        })
     }
 }
 
-/// This holds a pointer to a node by preserving identity (trait objects can't be compared equality
-/// because their vtable pointer is not stable).
-#[repr(transparent)]
-#[derive(Clone, Copy, Eq)]
-pub struct NodePtr(ptr::NonNull<dyn Node>);
+/// Identifies a node in diagnostics.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct NodeLabel {
+    pub id: NodeId,
+    pub name: Option<Arc<str>>,
+}
 
-impl PartialEq for NodePtr {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.cast::<()>() == other.0.cast()
+impl fmt::Display for NodeLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{name} ({})", self.id),
+            None => write!(f, "{}", self.id),
+        }
     }
 }
 
-impl hash::Hash for NodePtr {
-    fn hash<H: hash::Hasher>(&self, state: &mut H) {
-        self.0.cast::<()>().hash(state)
+/// A snapshot of a node for diagnostics.
+pub(crate) struct NodeInfo {
+    pub label: NodeLabel,
+    pub kind: NodeKind,
+    pub state: NodeState,
+    /// The `Debug` output of the value, if enabled with `Value::with_debug()` and available.
+    pub value: Option<String>,
+    /// The nodes read in the last evaluation.
+    pub dependencies: Vec<NodeId>,
+}
+
+/// A node on the evaluation stack.
+pub(crate) struct Frame {
+    pub node: NodeKey,
+    pub cycle_fallback: Option<Rc<dyn Any>>,
+    /// Cleared inside `Runtime::untrack()`.
+    pub tracking: bool,
+    /// The nodes read so far.
+    pub dependencies: Dependencies,
+    /// Set if the node read an outdated value that was borrowed. The node stays outdated after its
+    /// evaluation then.
+    pub stale: bool,
+}
+
+impl Frame {
+    pub fn new(node: NodeKey, cycle_fallback: Option<Rc<dyn Any>>) -> Self {
+        Frame {
+            node,
+            cycle_fallback,
+            tracking: true,
+            dependencies: Vec::new(),
+            stale: false,
+        }
     }
 }
 
-impl NodePtr {
-    pub fn new(node: &dyn Node) -> Self {
-        NodePtr(unsafe { ptr::NonNull::new_unchecked(node as *const dyn Node as *mut dyn Node) })
+/// The nodes a node read in its last evaluation, in the order they were first read. Locks them in
+/// memory via `Rc`.
+pub(crate) type Dependencies = Vec<Rc<dyn RefCellNode>>;
+
+/// A revision of the runtime. Starts at 0 and is incremented every time vars are changed.
+pub(crate) type Revision = u64;
+
+pub trait Node {
+    /// Records that the value of this node changed in `revision` and invalidates all its readers.
+    fn changed(&mut self, revision: Revision);
+    /// Brings this node up to date and returns the error if its evaluation failed.
+    fn update(&mut self) -> Result<(), Error>;
+    /// The revision the value of this node last changed in.
+    fn changed_at(&self) -> Revision;
+    /// Returns `true` if the node has a value and is not outdated.
+    fn is_valid(&self) -> bool;
+    /// Drops the trace of a computed node and marks it as disposed, so that it keeps its value and
+    /// is never updated again. Returns the dependencies, so that they can be dropped after the node
+    /// is released.
+    fn dispose(&mut self) -> Dependencies;
+    /// Drops the value and the trace of a computed node, so that it is evaluated again when it's
+    /// read. Returns the dependencies like `dispose()`.
+    fn reset(&mut self) -> Dependencies;
+    fn info(&self) -> NodeInfo;
+}
+
+pub trait RefCellNode {
+    fn borrow_mut(&self) -> RefMut<'_, dyn Node>;
+
+    /// Borrows the node, returns `None` if it is currently mutably borrowed, for example when it
+    /// is evaluating.
+    fn try_borrow(&self) -> Option<Ref<'_, dyn Node>>;
+
+    /// Brings the node up to date and returns the revision its value last changed in.
+    ///
+    /// If the node is borrowed, i.e. there is an active reference to its value, it can't be
+    /// updated. The revision of its current value is returned if it is valid anyway.
+    fn update(&self) -> Update;
+}
+
+/// The result of `RefCellNode::update()`.
+pub(crate) enum Update {
+    /// The node is up to date, its value last changed in the revision.
+    Current(Revision),
+    /// The node is outdated, but can't be updated because there is an active reference to its
+    /// value.
+    Borrowed,
+    /// The node is evaluating or verifying its dependencies, which means that it is part of a
+    /// cycle.
+    Cycle,
+}
+
+impl<T> RefCellNode for RefCell<T>
+where
+    T: Node + 'static,
+{
+    fn borrow_mut(&self) -> RefMut<'_, dyn Node> {
+        RefMut::map(self.borrow_mut(), |t| t as &mut dyn Node)
+    }
+
+    fn try_borrow(&self) -> Option<Ref<'_, dyn Node>> {
+        let node = self.try_borrow().ok()?;
+        Some(Ref::map(node, |t| t as &dyn Node))
     }
 
-    pub unsafe fn as_mut(&mut self) -> &mut dyn Node {
-        self.0.as_mut()
+    fn update(&self) -> Update {
+        match self.try_borrow_mut() {
+            Ok(mut node) => {
+                // Errors are stored like values, readers see them when they read the node.
+                let _ = node.update();
+                Update::Current(node.changed_at())
+            }
+            Err(_) => match self.try_borrow() {
+                Ok(node) if node.is_valid() => Update::Current(node.changed_at()),
+                Ok(_) => Update::Borrowed,
+                Err(_) => Update::Cycle,
+            },
+        }
     }
 }
 
-pub(crate) type Readers = HashSet<NodePtr>;
-pub(crate) type Trace = Vec<RefCellNodeHandle>;
-
 #[cfg(test)]
 mod tests {
     #[test]
@@ -187,4 +717,20 @@ mod tests {
         set.clear();
         assert!(set.capacity() >= 3);
     }
+
+    #[test]
+    fn batch_changes_share_one_revision() {
+        use super::Runtime;
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let mut b = rt.var(2);
+        let revision = rt.revision();
+        rt.batch(|| {
+            a.set(2);
+            b.set(3);
+        });
+        assert_eq!(rt.revision(), revision + 1);
+        a.set(3);
+        assert_eq!(rt.revision(), revision + 2);
+    }
 }
@@ -0,0 +1,290 @@
+use crate::{
+    runtime::{Dependencies, RefCellNode},
+    Runtime,
+};
+use std::{
+    cell::{Cell, RefCell},
+    mem,
+    rc::{Rc, Weak},
+};
+
+impl Runtime {
+    /// Runs `f` inside a new scope and returns the scope together with the result of `f`.
+    ///
+    /// All values and effects that are created inside `f` are owned by the scope. They are kept
+    /// alive until the scope is dropped, even if all their handles were dropped. Values that are
+    /// created by computed values while they evaluate inside `f` are not owned by the scope.
+    ///
+    /// Scopes that are created inside `f` are children of the scope and are disposed with it.
+    ///
+    /// The values of query tables and `#[query]` results are owned by their caches, not by the
+    /// scope they were first read in.
+    pub fn scope<R>(&self, f: impl FnOnce(&Scope) -> R) -> (Scope, R) {
+        // Leaves the scope even if `f` panics.
+        struct Leave<'a>(&'a Runtime);
+        impl Drop for Leave<'_> {
+            fn drop(&mut self) {
+                self.0.scopes().borrow_mut().pop();
+            }
+        }
+
+        let depth = self.depth();
+        let inner = Rc::new(ScopeInner {
+            depth,
+            nodes: Default::default(),
+            children: Default::default(),
+            on_dispose: Default::default(),
+            disposed: Cell::new(false),
+        });
+        let mut scopes = self.scopes().borrow_mut();
+        if let Some(parent) = scopes.last().filter(|parent| parent.depth == depth) {
+            parent.children.borrow_mut().push(Rc::downgrade(&inner));
+        }
+        scopes.push(inner.clone());
+        drop(scopes);
+
+        let scope = Scope(inner);
+        let r = {
+            let _leave = Leave(self);
+            f(&scope)
+        };
+        (scope, r)
+    }
+
+    /// Runs `f` outside of all scopes, so that the values it creates are not owned by any of them.
+    /// Used for values that are owned by caches.
+    pub(crate) fn unscoped<R>(&self, f: impl FnOnce() -> R) -> R {
+        // Reenters the scopes even if `f` panics.
+        struct Reenter<'a>(&'a Runtime, Vec<Rc<ScopeInner>>);
+        impl Drop for Reenter<'_> {
+            fn drop(&mut self) {
+                *self.0.scopes().borrow_mut() = mem::take(&mut self.1);
+            }
+        }
+
+        let scopes = mem::take(&mut *self.scopes().borrow_mut());
+        let _reenter = Reenter(self, scopes);
+        f()
+    }
+
+    /// Passes the ownership of `node` to the innermost scope, if it was entered outside of the
+    /// value that is currently evaluating.
+    pub(crate) fn adopt(&self, node: Rc<dyn RefCellNode>) {
+        let scopes = self.scopes().borrow();
+        if let Some(scope) = scopes.last().filter(|scope| scope.depth == self.depth()) {
+            scope.nodes.borrow_mut().push(node);
+        }
+    }
+}
+
+/// Owns the values and effects that were created inside `Runtime::scope()`.
+///
+/// Dropping the scope disposes them: Their traces are dropped and they are removed from the
+/// readers of their dependencies, so that they are never updated again. Computed values keep
+/// their last value and effects stop running. Computed values that were never evaluated fail with
+/// `Error::Disposed`. Values that still have handles outside the scope stay alive, all others are
+/// dropped.
+///
+/// Child scopes are disposed before their parent.
+pub struct Scope(Rc<ScopeInner>);
+
+pub(crate) struct ScopeInner {
+    /// The depth of the evaluation stack the scope was entered at.
+    depth: usize,
+    nodes: RefCell<Vec<Rc<dyn RefCellNode>>>,
+    children: RefCell<Vec<Weak<ScopeInner>>>,
+    on_dispose: RefCell<Vec<Box<dyn FnOnce()>>>,
+    disposed: Cell<bool>,
+}
+
+impl Scope {
+    /// Registers `f` to be invoked when the scope is disposed.
+    ///
+    /// Callbacks are invoked in the order they were registered, after the child scopes were
+    /// disposed and before the values of the scope are disposed.
+    pub fn on_dispose(&self, f: impl FnOnce() + 'static) {
+        self.0.on_dispose.borrow_mut().push(Box::new(f));
+    }
+
+    /// The number of values and effects that are owned by this scope, excluding the ones of its
+    /// children.
+    pub fn len(&self) -> usize {
+        self.0.nodes.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        self.0.dispose();
+    }
+}
+
+impl ScopeInner {
+    fn dispose(&self) {
+        if self.disposed.replace(true) {
+            return;
+        }
+        let children = mem::take(&mut *self.children.borrow_mut());
+        for child in children.iter().rev() {
+            if let Some(child) = child.upgrade() {
+                child.dispose();
+            }
+        }
+        let on_dispose = mem::take(&mut *self.on_dispose.borrow_mut());
+        for f in on_dispose {
+            f();
+        }
+
+        let nodes = mem::take(&mut *self.nodes.borrow_mut());
+        // The dependencies are dropped after all nodes were released, because dropping them
+        // might drop other nodes of this scope.
+        let dependencies: Vec<Dependencies> = nodes
+            .iter()
+            .map(|node| node.borrow_mut().dispose())
+            .collect();
+        drop(dependencies);
+        drop(nodes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, Runtime};
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+    };
+
+    #[test]
+    fn values_are_disposed_with_the_scope() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let (scope, b) = rt.scope(|_| {
+            let a = a.clone();
+            rt.computed(move || a.get() + 1)
+        });
+        let c = {
+            let a = a.clone();
+            rt.scope(|_| rt.computed(move || a.get() * 2)).0
+        };
+        assert_eq!(scope.len(), 1);
+        assert_eq!(b.get(), 2);
+        assert_eq!(a.readers_count(), 1);
+
+        drop(scope);
+        assert_eq!(a.readers_count(), 0);
+        a.set(2);
+        // Disposed values keep their last value.
+        assert_eq!(b.get(), 2);
+        assert_eq!(a.readers_count(), 0);
+
+        // Values that have no handles left are dropped with their scope.
+        assert_eq!(rt.nodes().len(), 3);
+        drop(c);
+        assert_eq!(rt.nodes().len(), 2);
+    }
+
+    #[test]
+    fn unevaluated_values_are_not_evaluated_after_their_scope_is_dropped() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let evaluations = Rc::new(Cell::new(0));
+        let (scope, b) = rt.scope(|_| {
+            let a = a.clone();
+            let evaluations = evaluations.clone();
+            rt.computed(move || {
+                evaluations.set(evaluations.get() + 1);
+                a.get() + 1
+            })
+        });
+        drop(scope);
+
+        let Err(Error::Disposed(error)) = b.try_get() else {
+            panic!("disposed error expected");
+        };
+        assert_eq!(error.node(), b.id());
+        assert_eq!(evaluations.get(), 0);
+        assert_eq!(a.readers_count(), 0);
+    }
+
+    #[test]
+    fn effects_run_until_their_scope_is_dropped() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let (scope, ()) = rt.scope(|_| {
+            let a = a.clone();
+            let seen = seen.clone();
+            // The scope keeps the effect alive.
+            drop(rt.effect(move || seen.borrow_mut().push(a.get())));
+        });
+        a.set(2);
+        rt.flush_effects();
+        assert_eq!(*seen.borrow(), [1, 2]);
+
+        a.set(3);
+        drop(scope);
+        rt.flush_effects();
+        assert_eq!(*seen.borrow(), [1, 2]);
+        assert_eq!(rt.nodes().len(), 1);
+    }
+
+    #[test]
+    fn nested_scopes_are_disposed_with_their_parent() {
+        let rt = Runtime::new();
+        let disposed = Rc::new(RefCell::new(Vec::new()));
+        let on_dispose = |scope: &crate::Scope, name: &'static str| {
+            let disposed = disposed.clone();
+            scope.on_dispose(move || disposed.borrow_mut().push(name));
+        };
+        let (parent, (first, _second)) = rt.scope(|scope| {
+            on_dispose(scope, "parent");
+            rt.var(0);
+            let first = rt.scope(|scope| {
+                on_dispose(scope, "first");
+                rt.var(1);
+            });
+            let second = rt.scope(|scope| {
+                on_dispose(scope, "second");
+                rt.var(2);
+            });
+            (first.0, second.0)
+        });
+        assert_eq!(parent.len(), 1);
+        assert_eq!(rt.nodes().len(), 3);
+
+        drop(first);
+        assert_eq!(*disposed.borrow(), ["first"]);
+        assert_eq!(rt.nodes().len(), 2);
+
+        drop(parent);
+        assert_eq!(*disposed.borrow(), ["first", "second", "parent"]);
+        assert_eq!(rt.nodes().len(), 0);
+    }
+
+    #[test]
+    fn values_created_while_evaluating_are_not_owned_by_the_scope() {
+        let rt = Runtime::new();
+        let created = Rc::new(Cell::new(0));
+        let (scope, outer) = rt.scope(|_| {
+            let rt = rt.clone();
+            let created = created.clone();
+            rt.clone().computed(move || {
+                let inner = rt.var(1);
+                created.set(created.get() + 1);
+                inner.get()
+            })
+        });
+        assert_eq!(outer.get(), 1);
+        assert_eq!(created.get(), 1);
+        assert_eq!(scope.len(), 1);
+        // The inner var is kept alive by the trace of the outer value only.
+        assert_eq!(rt.nodes().len(), 2);
+        drop(scope);
+        assert_eq!(rt.nodes().len(), 1);
+    }
+}
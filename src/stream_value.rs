@@ -1,4 +1,4 @@
-use std::{cell::RefCell, iter, rc::Rc};
+use std::{cell::RefCell, iter};
 
 use crate::{stream, Runtime, Value};
 
@@ -6,8 +6,6 @@ use crate::{stream, Runtime, Value};
 /// produce and return a consumer. The consumer receivers all new values that are produced by the
 /// producer.
 pub type Producer<T> = Value<stream::Producer<T>>;
-// TODO: There should be Value that preserves its value on invalidation, and the compute function
-// should take the old value (if existing). This way we could remove the reference counter here.
 pub type Consumer<T> = Value<ConsumerValue<T>>;
 
 impl Runtime {
@@ -19,12 +17,14 @@ impl Runtime {
 
 impl<T> Producer<T> {
     pub fn subscribe(&self) -> Consumer<T> {
-        let producer = self.get_ref();
-        let consumer = ConsumerValue::new(producer.subscribe());
+        let mut consumer = Some(ConsumerValue::new(self.get_ref().subscribe()));
         let producer = self.clone();
-        self.runtime().computed(move || {
+        self.runtime().computed_with_prev(move |prev| {
             producer.track();
-            consumer.clone()
+            // The consumer is moved from one evaluation to the next. If it got lost, because the
+            // value was taken, consumption continues with the values produced from now on.
+            prev.or_else(|| consumer.take())
+                .unwrap_or_else(|| ConsumerValue::new(producer.get_ref().subscribe()))
         })
     }
 
@@ -36,17 +36,11 @@ impl<T> Producer<T> {
     }
 }
 
-pub struct ConsumerValue<T>(Rc<RefCell<stream::Consumer<T>>>);
-
-impl<T> Clone for ConsumerValue<T> {
-    fn clone(&self) -> Self {
-        ConsumerValue(self.0.clone())
-    }
-}
+pub struct ConsumerValue<T>(RefCell<stream::Consumer<T>>);
 
 impl<T> ConsumerValue<T> {
     pub fn new(consumer: stream::Consumer<T>) -> Self {
-        ConsumerValue(Rc::new(RefCell::new(consumer)))
+        ConsumerValue(RefCell::new(consumer))
     }
 
     pub fn drain(&self) -> impl Iterator<Item = T> + '_
@@ -0,0 +1,1160 @@
+//! A thread-safe flavour of the runtime.
+//!
+//! `sync::Runtime` and `sync::Value` have the same API as their single threaded counterparts, but
+//! values can be shared between threads. Vars can be set from any thread, and computed values are
+//! evaluated by the thread that reads them. Values that are read while another thread evaluates
+//! them are waited for. Independent values can be evaluated in parallel with `Runtime::join()` and
+//! `Runtime::par_get()`.
+//!
+//! Contained values are stored in an `Arc` and `Value::get_ref()` returns a clone of that `Arc`
+//! instead of a borrow, so that no lock needs to be held while a value is referenced.
+//!
+//! Dependency cycles that span multiple threads are not detected and deadlock.
+
+mod effect;
+mod join;
+mod pool;
+mod runtime;
+mod value;
+
+pub use effect::Effect;
+pub use runtime::Runtime;
+pub use value::Value;
+
+#[cfg(test)]
+mod tests {
+    use super::{Runtime, Value};
+    use crate::{map, memo, try_map, Error};
+    use std::{
+        fmt,
+        panic::{self, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Barrier, Mutex,
+        },
+        thread,
+    };
+
+    #[test]
+    fn add_two_vars() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let mut b = rt.var(2);
+
+        let c = map!(|a, b| a + b);
+        assert_eq!(c.get(), 3);
+        b.set(3);
+        assert_eq!(c.get(), 4);
+    }
+
+    #[test]
+    fn diamond_problem() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let b = map!(|a| a * 2);
+        let c = map!(|a| a * 3);
+        let evaluation_count = Arc::new(AtomicUsize::new(0));
+        let d = {
+            let ec = evaluation_count.clone();
+            map!(|b, c| {
+                ec.fetch_add(1, Ordering::Relaxed);
+                b + c
+            })
+        };
+        assert_eq!(d.get(), 5);
+        assert_eq!(evaluation_count.load(Ordering::Relaxed), 1);
+
+        a.set(2);
+        assert_eq!(d.get(), 10);
+        assert_eq!(evaluation_count.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn readers_are_removed_when_computed_is_dropped() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let b = map!(|a| a * 2);
+        // b is not evaluated yet, so no readers.
+        assert_eq!(a.readers_count(), 0);
+        // Now we evaluate b, so it has a reader.
+        assert_eq!(b.get(), 2);
+        assert_eq!(a.readers_count(), 1);
+        // Now we drop b, so it should remove its reader.
+        drop(b);
+        assert_eq!(a.readers_count(), 0);
+    }
+
+    /// Test support support of the "switching pattern".
+    /// See adapton: https://docs.rs/adapton/latest/adapton/#demand-driven-change-propagation
+    #[test]
+    fn div_check() {
+        let rt = Runtime::new();
+
+        // Two mutable inputs, for numerator and denominator of division
+        let num = rt.var(42);
+        let mut den = rt.var(2);
+
+        // Two sub computations: The division, and a check thunk with a conditional expression
+        let div = map!(|*num, *den| num / den);
+        let check = map!(|*den| if den == 0 { None } else { Some(div.get()) });
+
+        // Observe output of `check` while we change the input `den`
+        assert_eq!(check.get(), Some(21));
+
+        den.set(0);
+        assert_eq!(check.get(), None);
+
+        den.set(2);
+        assert_eq!(check.get(), Some(21)); // division is used again
+    }
+
+    /// Test for the "switching pattern" by checking `is_valid()`.
+    #[test]
+    fn changed_but_subsequently_subsequently_ignored_dependency_is_not_validated() {
+        let rt = Runtime::new();
+        let mut a = rt.var("a");
+        let ac = map!(|*a| a);
+        let mut switch = rt.var(false);
+        let b = rt.var("b");
+        let r = {
+            let ac = ac.clone();
+            let b = b.clone();
+            map!(|switch| if !switch { ac.get() } else { b.get() })
+        };
+
+        assert_eq!(r.get(), "a");
+
+        {
+            a.set("aa");
+            switch.set(true);
+        }
+
+        assert_eq!(r.get(), "b");
+        assert!(!ac.is_valid());
+    }
+
+    /// Drop `a` in a computation after it was read.
+    #[test]
+    fn drop_var_after_read_in_computed() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let c = {
+            let mut a = Some(a);
+            rt.computed(move || {
+                // read from a.
+                let r = a.as_ref().unwrap().get();
+                // Drop a, even though it has readers.
+                a = None;
+                r
+            })
+        };
+        assert_eq!(c.get(), 1);
+    }
+
+    #[test]
+    fn recorded_reader_gets_dropped() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+
+        let drop_counter = Arc::new(());
+
+        let b = {
+            let r = drop_counter.clone();
+            map!(|a| {
+                let _b = a;
+                r.clone()
+            })
+        };
+
+        b.get();
+        assert_eq!(Arc::strong_count(&drop_counter), 3);
+        assert!(b.is_valid());
+        assert_eq!(a.readers_count(), 1);
+
+        drop(b);
+        assert_eq!(a.readers_count(), 0);
+        assert_eq!(Arc::strong_count(&drop_counter), 1);
+    }
+
+    #[test]
+    fn simple_computed_macro() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let b = rt.var(2);
+        let r = {
+            let rt = rt.clone();
+            map!(|a, b| {
+                let _just_here_to_see_if_clone_to_computed_works = rt.var(1);
+                a + b
+            })
+        };
+        assert_eq!(r.get(), 3);
+        let c = rt.var(3);
+        let r = map!(|a, b, c| a + b + c);
+        assert_eq!(r.get(), 6);
+    }
+
+    #[test]
+    fn simple_memo_macro() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let count = Arc::new(AtomicUsize::new(0));
+        let c = {
+            let count = count.clone();
+            map!(|a| {
+                count.fetch_add(1, Ordering::Relaxed);
+                a + 1
+            })
+        };
+
+        assert_eq!(c.get(), 2);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        // be sure the invalidation gets through (set might later check for equality)
+        a.set(2);
+        a.set(1);
+        assert_eq!(c.get(), 2);
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+
+        count.store(0, Ordering::Relaxed);
+
+        let c = {
+            let count = count.clone();
+            memo!(|a| {
+                count.fetch_add(1, Ordering::Relaxed);
+                a + 1
+            })
+        };
+
+        assert_eq!(c.get(), 2);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        // be sure the invalidation gets through (set might later check for equality)
+        a.set(2);
+        a.set(1);
+        assert_eq!(c.get(), 2);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    // Two references (retrieved with `get_ref()`) to the same value must be able to exist at the
+    // same time.
+    #[test]
+    fn two_get_refs() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        // 'b' is evaluated second and retrieves another reference to `a`
+        let b = map!(|a| *a);
+        // 'r' is evaluated first and keeps a reference to `a` and then evaluates `b`.
+        let r = map!(|a, b| a + b);
+        assert_eq!(r.get(), 2);
+        // And `a` must be read twice.
+        assert_eq!(a.readers_count(), 2);
+    }
+
+    #[test]
+    fn batch_invalidates_readers_once() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let mut b = rt.var(2);
+        let evaluation_count = Arc::new(AtomicUsize::new(0));
+        let c = {
+            let ec = evaluation_count.clone();
+            map!(|a, b| {
+                ec.fetch_add(1, Ordering::Relaxed);
+                a + b
+            })
+        };
+        assert_eq!(c.get(), 3);
+
+        rt.batch(|| {
+            a.set(2);
+            b.set(3);
+            // Invalidation is postponed until the batch commits.
+            assert!(c.is_valid());
+            assert_eq!(a.readers_count(), 1);
+        });
+
+        assert!(!c.is_valid());
+        assert_eq!(c.get(), 5);
+        assert_eq!(evaluation_count.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn nested_batches_commit_with_the_outermost_batch() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let c = map!(|a| a * 2);
+        assert_eq!(c.get(), 2);
+
+        rt.batch(|| {
+            rt.batch(|| a.set(2));
+            assert_eq!(c.get(), 2);
+        });
+
+        assert_eq!(c.get(), 4);
+    }
+
+    #[test]
+    fn panicking_batch_commits_changes() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let c = map!(|a| a * 2);
+        assert_eq!(c.get(), 2);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            rt.batch(|| {
+                let mut a = a.clone();
+                a.set(2);
+                panic!("inside batch");
+            })
+        }));
+
+        assert!(result.is_err());
+        assert!(!rt.is_batching());
+        assert_eq!(c.get(), 4);
+    }
+
+    #[test]
+    fn var_eq_does_not_invalidate_on_equal_value() {
+        let rt = Runtime::new();
+        let mut a = rt.var_eq(1);
+        let count = Arc::new(AtomicUsize::new(0));
+        let c = {
+            let count = count.clone();
+            map!(|a| {
+                count.fetch_add(1, Ordering::Relaxed);
+                a + 1
+            })
+        };
+
+        assert_eq!(c.get(), 2);
+        a.set(1);
+        assert!(c.is_valid());
+        assert_eq!(c.get(), 2);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        a.set(2);
+        assert_eq!(c.get(), 3);
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn set_if_changed() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let c = map!(|a| a + 1);
+        assert_eq!(c.get(), 2);
+        a.set_if_changed(1);
+        assert!(c.is_valid());
+        a.set_if_changed(2);
+        assert!(!c.is_valid());
+        assert_eq!(c.get(), 3);
+    }
+
+    #[test]
+    fn computed_eq_cuts_off_recomputation() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let parity = {
+            let a = a.clone();
+            rt.computed_eq(move || a.get() % 2)
+        };
+        let count = Arc::new(AtomicUsize::new(0));
+        let c = {
+            let count = count.clone();
+            map!(|parity| {
+                count.fetch_add(1, Ordering::Relaxed);
+                *parity
+            })
+        };
+
+        assert_eq!(c.get(), 1);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        // `parity` gets recomputed, but does not change, so `c` stays the same.
+        a.set(3);
+        assert!(!c.is_valid());
+        assert_eq!(c.get(), 1);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert!(parity.is_valid());
+
+        a.set(4);
+        assert_eq!(c.get(), 0);
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
+
+    /// Dependencies that are not equality-checked still cause a recomputation in a diamond, even
+    /// if an equality-checked one did not change.
+    #[test]
+    fn computed_eq_in_diamond() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let parity = {
+            let a = a.clone();
+            rt.computed_eq(move || a.get() % 2)
+        };
+        let r = map!(|parity, a| parity + a);
+        assert_eq!(r.get(), 2);
+        a.set(3);
+        assert_eq!(r.get(), 4);
+    }
+
+    /// A change that is cut off at the start of a deep chain does not cause any recomputation of
+    /// the chain, the values are verified instead.
+    #[test]
+    fn deep_chain_is_verified_without_recomputation() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let parity = {
+            let a = a.clone();
+            rt.computed_eq(move || a.get() % 2)
+        };
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut chain = parity.clone();
+        for _ in 0..10 {
+            let count = count.clone();
+            chain = map!(|chain| {
+                count.fetch_add(1, Ordering::Relaxed);
+                chain + 1
+            });
+        }
+
+        assert_eq!(chain.get(), 11);
+        assert_eq!(count.load(Ordering::Relaxed), 10);
+
+        a.set(3);
+        assert_eq!(chain.get(), 11);
+        assert_eq!(count.load(Ordering::Relaxed), 10);
+
+        a.set(2);
+        assert_eq!(chain.get(), 10);
+        assert_eq!(count.load(Ordering::Relaxed), 20);
+    }
+
+    /// A var that is changed while a computed value reads it, causes the computed value to be
+    /// recomputed the next time it is read.
+    #[test]
+    fn var_changed_while_computing() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let c = {
+            let a = a.clone();
+            rt.computed(move || {
+                let r = a.get();
+                if r == 1 {
+                    a.clone().set(2);
+                }
+                r
+            })
+        };
+        assert_eq!(c.get(), 1);
+        assert!(!c.is_valid());
+        assert_eq!(c.get(), 2);
+        assert!(c.is_valid());
+    }
+
+    /// A value that is set after the values that refer to it are created. Used to create cycles.
+    ///
+    /// Dropping a handle clears the value, so that the values in the cycle are freed.
+    pub(super) struct Late<T: 'static>(Arc<Mutex<Option<Value<T>>>>);
+
+    impl<T> Late<T> {
+        pub fn new() -> Self {
+            Late(Default::default())
+        }
+
+        pub fn set(&self, value: Value<T>) {
+            *self.0.lock().unwrap() = Some(value);
+        }
+
+        pub fn get(&self) -> Value<T> {
+            self.0.lock().unwrap().clone().unwrap()
+        }
+    }
+
+    impl<T> Clone for Late<T> {
+        fn clone(&self) -> Self {
+            Late(self.0.clone())
+        }
+    }
+
+    impl<T> Drop for Late<T> {
+        fn drop(&mut self) {
+            // Poisoned by tests that panic while the value is locked.
+            let value = match self.0.lock() {
+                Ok(mut value) => value.take(),
+                Err(poisoned) => poisoned.into_inner().take(),
+            };
+            drop(value);
+        }
+    }
+
+    #[test]
+    fn self_cycle_is_detected() {
+        let rt = Runtime::new();
+        let this: Late<Result<i32, Error>> = Late::new();
+        let a = {
+            let this = this.clone();
+            rt.computed(move || {
+                let _ = this.get().try_get()?;
+                Ok(1)
+            })
+        };
+        this.set(a.clone());
+
+        let Err(Error::Cycle(error)) = a.get() else {
+            panic!("cycle expected");
+        };
+        assert_eq!(error.nodes(), [a.id()]);
+    }
+
+    #[test]
+    fn cycle_is_detected() {
+        let rt = Runtime::new();
+        let late_a: Late<i32> = Late::new();
+        let b = {
+            let late_a = late_a.clone();
+            rt.computed(move || late_a.get().try_get().map(|a| a + 1))
+                .named("b")
+        };
+        let a = map!(|b| b.clone().unwrap_or(0)).named("a");
+        late_a.set(a.clone());
+
+        assert_eq!(a.get(), 0);
+        let Err(Error::Cycle(error)) = b.get() else {
+            panic!("cycle expected");
+        };
+        assert_eq!(error.nodes(), [a.id(), b.id()]);
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Cycle detected: a ({}) -> b ({}) -> a ({})",
+                a.id(),
+                b.id(),
+                a.id()
+            )
+        );
+    }
+
+    /// A cycle that is closed by a dependency that changes between revisions is detected while
+    /// the values are verified.
+    #[test]
+    fn cycle_closed_by_a_changed_dependency_is_detected() {
+        let rt = Runtime::new();
+        let mut closed = rt.var(false);
+        let late_a: Late<i32> = Late::new();
+        let b = {
+            let late_a = late_a.clone();
+            let closed = closed.clone();
+            rt.computed(move || match closed.get() {
+                true => late_a.get().try_get().map(|a| a + 1),
+                false => Ok(0),
+            })
+            .named("b")
+        };
+        let a = map!(|b| b.clone().unwrap_or(0)).named("a");
+        late_a.set(a.clone());
+        assert_eq!(a.get(), 0);
+
+        // `a` is verified first, `b` reads it while `a` verifies its dependencies.
+        closed.set(true);
+        assert_eq!(a.get(), 0);
+        let Err(Error::Cycle(error)) = b.get() else {
+            panic!("cycle expected");
+        };
+        assert_eq!(error.nodes(), [a.id(), b.id()]);
+
+        // `b` is verified first and reads `a`, which verifies `b` while it is evaluating. `a`
+        // reads `b` with `get()` and panics with the cycle.
+        closed.set(false);
+        assert_eq!(b.get().unwrap(), 0);
+        assert_eq!(a.get(), 0);
+        closed.set(true);
+        let Err(Error::Poisoned(error)) = b.get() else {
+            panic!("poisoned value expected");
+        };
+        assert_eq!(error.node(), a.id());
+        assert_eq!(
+            error.message(),
+            format!(
+                "Cycle detected: b ({}) -> a ({}) -> b ({})",
+                b.id(),
+                a.id(),
+                b.id()
+            )
+        );
+        assert!(matches!(a.try_get(), Err(Error::Poisoned(_))));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cycle detected")]
+    fn get_panics_on_cycle() {
+        let rt = Runtime::new();
+        let late_a: Late<i32> = Late::new();
+        let a = {
+            let late_a = late_a.clone();
+            rt.computed(move || late_a.get().get())
+        };
+        late_a.set(a.clone());
+        a.get();
+    }
+
+    #[test]
+    fn cycle_fallback() {
+        let rt = Runtime::new();
+        let late_a: Late<i32> = Late::new();
+        let late_b: Late<i32> = Late::new();
+        let a = {
+            let late_b = late_b.clone();
+            rt.computed_with_cycle_fallback(move || late_b.get().get() + 1, 0)
+        };
+        let b = {
+            let late_a = late_a.clone();
+            rt.computed(move || late_a.get().get() * 2 + 1)
+        };
+        late_a.set(a.clone());
+        late_b.set(b.clone());
+
+        // `b` reads the fallback of `a`.
+        assert_eq!(a.get(), 2);
+        assert_eq!(b.get(), 1);
+    }
+
+    #[test]
+    fn panicking_computed_is_poisoned_until_an_input_changes() {
+        let rt = Runtime::new();
+        let mut a = rt.var(0);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let c = {
+            let runs = runs.clone();
+            map!(|*a| {
+                runs.fetch_add(1, Ordering::Relaxed);
+                if a == 0 {
+                    panic!("division by zero");
+                }
+                10 / a
+            })
+        };
+
+        let Err(Error::Poisoned(error)) = c.try_get() else {
+            panic!("poisoned value expected");
+        };
+        assert_eq!(error.node(), c.id());
+        assert_eq!(error.message(), "division by zero");
+        assert!(!rt.is_evaluating());
+
+        // The poisoned value is not recomputed.
+        assert!(c.try_get().is_err());
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+
+        a.set(2);
+        assert_eq!(c.get(), 5);
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn poisoned_value_is_invalidated_by_the_dependencies_read_before_the_panic() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let mut b = rt.var(10);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let c = {
+            let (a, b) = (a.clone(), b.clone());
+            let runs = runs.clone();
+            rt.computed(move || {
+                runs.fetch_add(1, Ordering::Relaxed);
+                let a = a.get();
+                if a == 0 {
+                    panic!("division by zero");
+                }
+                b.get() / a
+            })
+        };
+        assert_eq!(c.get(), 10);
+        assert_eq!(b.readers_count(), 1);
+
+        a.set(0);
+        assert!(c.try_get().is_err());
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+        // `b` was read by the previous run only, so it does not invalidate the poisoned value.
+        assert_eq!(b.readers_count(), 0);
+        b.set(20);
+        assert!(c.try_get().is_err());
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+
+        a.set(2);
+        assert_eq!(c.get(), 10);
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+        assert_eq!(b.readers_count(), 1);
+    }
+
+    #[test]
+    fn get_of_poisoned_value_panics() {
+        let rt = Runtime::new();
+        let c = rt.computed(|| -> i32 { panic!("boom") });
+        assert!(c.try_get().is_err());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| c.get()));
+        let message = result.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.ends_with("panicked: boom"));
+    }
+
+    /// A panic in a dependency poisons all values that read it, and all of them recover when the
+    /// dependency's input changes.
+    #[test]
+    fn poison_propagates_to_readers() {
+        let rt = Runtime::new();
+        let mut a = rt.var(0);
+        let b = map!(|*a| {
+            assert!(a != 0, "zero");
+            a
+        });
+        let c = map!(|*b| b + 1);
+
+        let Err(Error::Poisoned(error)) = c.try_get() else {
+            panic!("poisoned value expected");
+        };
+        assert_eq!(error.node(), c.id());
+        assert!(error.message().ends_with("panicked: zero"));
+        assert_eq!(b.readers_count(), 1);
+
+        a.set(1);
+        assert_eq!(c.get(), 2);
+    }
+
+    #[derive(Debug)]
+    struct NegativeError;
+
+    impl fmt::Display for NegativeError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "negative")
+        }
+    }
+
+    impl std::error::Error for NegativeError {}
+
+    #[test]
+    fn failed_computed_is_cached_until_an_input_changes() {
+        let rt = Runtime::new();
+        let mut a = rt.var(-1);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let sqrt = {
+            let a = a.clone();
+            let runs = runs.clone();
+            rt.try_computed(move || {
+                runs.fetch_add(1, Ordering::Relaxed);
+                let a = a.get();
+                if a < 0 {
+                    return Err(Error::failed(NegativeError));
+                }
+                Ok((a as f64).sqrt())
+            })
+        };
+        let doubled = try_map!(|*sqrt| Ok(sqrt * 2.0));
+
+        let Err(Error::Failed(error)) = doubled.try_get() else {
+            panic!("failure expected");
+        };
+        assert_eq!(error.to_string(), "negative");
+        assert!(doubled.try_get().is_err());
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+
+        a.set(4);
+        assert_eq!(doubled.try_get().unwrap(), 4.0);
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn try_map_macro() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let b = rt.var(String::from("2"));
+        let c = try_map!(|*a, b| {
+            let b: i32 = b.parse().map_err(Error::failed)?;
+            Ok(a + b)
+        });
+        assert_eq!(c.try_get().unwrap(), 3);
+        b.clone().set("x".into());
+        assert!(matches!(c.try_get(), Err(Error::Failed(_))));
+    }
+
+    #[test]
+    fn names() {
+        let rt = Runtime::new();
+        let a = rt.var_named("a", 1);
+        let b = rt.var(2);
+        let (c, line) = (map!(|a, b| a + b), line!());
+        assert_eq!(a.name().as_deref(), Some("a"));
+        assert_eq!(b.name(), None);
+        assert_eq!(c.name().unwrap().to_string(), format!("{}:{line}", file!()));
+    }
+
+    #[test]
+    fn debug_and_display() {
+        let rt = Runtime::new();
+        let a = rt.var_named("a", 1);
+        let b = map!(|a| a + 1).named("b");
+        assert_eq!(
+            format!("{a:?}"),
+            format!(
+                r#"Value {{ id: {:?}, name: Some("a"), kind: var, state: valid, value: 1 }}"#,
+                a.id()
+            )
+        );
+        assert_eq!(
+            format!("{b:?}"),
+            format!(
+                r#"Value {{ id: {:?}, name: Some("b"), kind: computed, state: unevaluated }}"#,
+                b.id()
+            )
+        );
+        assert_eq!(b.get(), 2);
+        assert_eq!(
+            format!("{b:?}"),
+            format!(
+                r#"Value {{ id: {:?}, name: Some("b"), kind: computed, state: valid, value: 2 }}"#,
+                b.id()
+            )
+        );
+        assert_eq!(b.to_string(), format!("b ({}): computed, valid", b.id()));
+    }
+
+    #[test]
+    fn values_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Runtime>();
+        assert_send_sync::<Value<i32>>();
+        assert_send_sync::<Error>();
+    }
+
+    #[test]
+    fn var_set_from_another_thread() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let c = map!(|a| a * 2);
+        assert_eq!(c.get(), 2);
+
+        thread::spawn({
+            let mut a = a.clone();
+            move || a.set(2)
+        })
+        .join()
+        .unwrap();
+
+        assert!(!c.is_valid());
+        assert_eq!(c.get(), 4);
+    }
+
+    #[test]
+    fn concurrent_reads_evaluate_once() {
+        const THREADS: usize = 8;
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let count = Arc::new(AtomicUsize::new(0));
+        let c = {
+            let count = count.clone();
+            map!(|a| {
+                count.fetch_add(1, Ordering::Relaxed);
+                // Give the other threads time to run into the evaluation.
+                thread::sleep(std::time::Duration::from_millis(10));
+                a + 1
+            })
+        };
+
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let readers: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let c = c.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    c.get()
+                })
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), 2);
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn concurrent_writers_and_readers() {
+        let rt = Runtime::new();
+        let vars: Vec<_> = (0..4).map(|_| rt.var(0)).collect();
+        let sum = {
+            let vars = vars.clone();
+            rt.computed(move || vars.iter().map(|var| var.get()).sum::<i32>())
+        };
+
+        let writers: Vec<_> = vars
+            .iter()
+            .map(|var| {
+                let mut var = var.clone();
+                thread::spawn(move || {
+                    for i in 1..=100 {
+                        var.set(i);
+                    }
+                })
+            })
+            .collect();
+        let reader = {
+            let sum = sum.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    assert!((0..=400).contains(&sum.get()));
+                }
+            })
+        };
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        reader.join().unwrap();
+
+        assert_eq!(sum.get(), 400);
+    }
+
+    #[test]
+    fn export_dot() {
+        let rt = Runtime::new();
+        let a = rt.var_named("a", 1).with_debug();
+        let b = map!(|a| a + 1).named("b");
+        assert_eq!(b.get(), 2);
+        let expected = [
+            "digraph {",
+            r##"    n0 [label="a (#0) var\nvalid\n1", style=solid];"##,
+            r##"    n1 [label="b (#1) computed\nvalid", style=solid];"##,
+            "    n0 -> n1;",
+            "}",
+            "",
+        ];
+        assert_eq!(rt.export_dot(), expected.join("\n"));
+    }
+
+    #[test]
+    fn invalidation_cause() {
+        let rt = Runtime::new();
+        rt.trace_invalidations(true);
+        let mut a = rt.var(1);
+        let b = map!(|a| a + 1);
+        b.get();
+        let line = line!() + 1;
+        a.set(2);
+        let cause = b.last_invalidation_cause().unwrap();
+        assert_eq!(cause.path(), [a.id(), b.id()]);
+        assert_eq!(cause.location().line(), line);
+    }
+
+    #[test]
+    fn untracked_reads_are_not_dependencies() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let mut b = rt.var(2);
+        let c = rt.var(3);
+        let sum = {
+            let (a, b, c) = (a.clone(), b.clone(), c.clone());
+            rt.computed(move || a.get() + b.get_untracked() + *c.peek())
+        };
+
+        assert_eq!(sum.get(), 6);
+        assert_eq!(sum.trace_len(), 1);
+        assert_eq!(a.readers_count(), 1);
+        assert_eq!(b.readers_count(), 0);
+        assert_eq!(c.readers_count(), 0);
+
+        b.set(3);
+        assert!(sum.is_valid());
+        assert_eq!(sum.get(), 6);
+
+        a.set(2);
+        assert_eq!(sum.get(), 8);
+    }
+
+    #[test]
+    fn untrack_scope() {
+        let _exclusive = super::pool::exclusive();
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let mut b = rt.var(2);
+        let b2 = map!(|b| b * 2);
+        let sum = {
+            let rt = rt.clone();
+            let (a, b2) = (a.clone(), b2.clone());
+            rt.clone()
+                .computed(move || a.get() + rt.untrack(|| rt.join(|| b2.get(), || 0).0))
+        };
+
+        assert_eq!(sum.get(), 5);
+        assert_eq!(sum.trace_len(), 1);
+        assert_eq!(b2.readers_count(), 0);
+        // Values evaluated inside the scope still track their own reads.
+        assert_eq!(b.readers_count(), 1);
+        assert_eq!(b2.trace_len(), 1);
+
+        b.set(3);
+        assert!(!b2.is_valid());
+        assert!(sum.is_valid());
+        assert_eq!(sum.get(), 5);
+    }
+
+    /// Values that are read in a hot loop, also on other threads, are recorded once per
+    /// evaluation, so the trace stays bounded by the number of distinct dependencies.
+    #[test]
+    fn hot_loop_reads_are_traced_once() {
+        const READS: i32 = 100;
+        let _exclusive = super::pool::exclusive();
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let b = rt.var(2);
+        let sum = {
+            let rt = rt.clone();
+            let (a, b) = (a.clone(), b.clone());
+            rt.clone().computed(move || {
+                (0..READS)
+                    .map(|_| {
+                        let (a, b) = rt.join(|| a.get(), || b.get());
+                        a + b + a
+                    })
+                    .sum::<i32>()
+            })
+        };
+
+        for i in 1..5 {
+            a.set(i);
+            assert_eq!(sum.get(), (i * 2 + 2) * READS);
+            assert_eq!(sum.trace_len(), 2);
+            assert_eq!(a.readers_count(), 1);
+        }
+    }
+
+    #[test]
+    fn computed_with_prev() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let history = {
+            let a = a.clone();
+            rt.computed_with_prev(move |prev: Option<Vec<i32>>| {
+                let mut history = prev.unwrap_or_default();
+                history.push(a.get());
+                history
+            })
+        };
+
+        assert_eq!(history.get(), [1]);
+        a.set(2);
+        a.set(3);
+        assert_eq!(history.get(), [1, 3]);
+        // The previous value is lost when it is taken.
+        assert_eq!(history.clone().take(), [1, 3]);
+        assert_eq!(history.get(), [3]);
+    }
+
+    #[test]
+    fn fold() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let sum = {
+            let a = a.clone();
+            rt.fold(10, move |sum| sum + a.get())
+        };
+        assert_eq!(sum.get(), 11);
+        assert_eq!(sum.get(), 11);
+        a.set(2);
+        assert_eq!(sum.get(), 13);
+        a.set(3);
+        assert_eq!(sum.get(), 16);
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Window {
+        title: String,
+        size: (u32, u32),
+    }
+
+    #[test]
+    fn lens() {
+        let rt = Runtime::new();
+        let window = rt.var(Window {
+            title: "untitled".into(),
+            size: (640, 480),
+        });
+        let mut title = window.lens(|w| &w.title, |w, title| w.title = title);
+        let mut width = window
+            .lens(|w| &w.size, |w, size| w.size = size)
+            .lens(|s| &s.0, |s, width| s.0 = width);
+        let area = map!(|window| window.size.0 * window.size.1);
+
+        assert_eq!(title.get(), "untitled");
+        assert_eq!(width.get(), 640);
+        assert_eq!(area.get(), 640 * 480);
+
+        title.set("granularity".into());
+        assert_eq!(window.get_ref().title, "granularity");
+        assert_eq!(title.get(), "granularity");
+
+        width.apply(|width| width * 2);
+        assert_eq!(window.get_ref().size, (1280, 480));
+        assert_eq!(width.get(), 1280);
+        assert_eq!(area.get(), 1280 * 480);
+    }
+
+    #[test]
+    fn lens_set_if_changed_compares_with_the_source() {
+        let rt = Runtime::new();
+        let mut window = rt.var(Window {
+            title: "a".into(),
+            size: (1, 1),
+        });
+        let mut title = window.lens(|w| &w.title, |w, title| w.title = title);
+        let reader = map!(|window| window.size);
+        assert_eq!(title.get(), "a");
+        assert_eq!(reader.get(), (1, 1));
+
+        // The lens still holds "a".
+        window.apply(|w| Window {
+            title: "b".into(),
+            ..w
+        });
+        assert_eq!(reader.get(), (1, 1));
+        title.set_if_changed("b".into());
+        assert!(reader.is_valid());
+        title.set_if_changed("a".into());
+        assert!(!reader.is_valid());
+        assert_eq!(window.get_ref().title, "a");
+    }
+
+    #[test]
+    fn projections_recompute_only_readers_of_changed_fields() {
+        let rt = Runtime::new();
+        let window = rt.var(Window {
+            title: "a".into(),
+            size: (1, 1),
+        });
+        let mut title = window.project(|w| &w.title, |w, title| w.title = title);
+        let mut size = window.project(|w| &w.size, |w, size| w.size = size);
+        let evaluation_count = Arc::new(AtomicUsize::new(0));
+        let title_len = {
+            let ec = evaluation_count.clone();
+            map!(|title| {
+                ec.fetch_add(1, Ordering::Relaxed);
+                title.len()
+            })
+        };
+        let area = map!(|size| size.0 * size.1);
+        assert_eq!(title_len.get(), 1);
+        assert_eq!(area.get(), 1);
+
+        size.set((2, 3));
+        assert_eq!(area.get(), 6);
+        assert_eq!(title_len.get(), 1);
+        assert_eq!(evaluation_count.load(Ordering::Relaxed), 1);
+
+        title.set("abc".into());
+        assert_eq!(title_len.get(), 3);
+        assert_eq!(evaluation_count.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            window.get(),
+            Window {
+                title: "abc".into(),
+                size: (2, 3),
+            }
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use super::{Runtime, Value};
+
+/// An effect of a `sync::Runtime`.
+///
+/// See `crate::Effect`. Effects run on the thread that calls `Runtime::flush_effects()` or commits
+/// a batch.
+pub struct Effect {
+    // The effect node. It is only referenced from here, so dropping the effect drops the node and
+    // removes it from the readers of its dependencies.
+    _value: Value<()>,
+}
+
+impl Runtime {
+    /// Creates an effect and runs it for the first time.
+    ///
+    /// All values that are read while `run` is executed are tracked like in a computed value.
+    ///
+    /// Panics if `run` panics.
+    pub fn effect(&self, run: impl FnMut() + Send + 'static) -> Effect {
+        let value = Value::new_effect(self, run);
+        if let Err(e) = value.update() {
+            panic!("{e}");
+        }
+        Effect { _value: value }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sync::Runtime;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn effect_runs_on_flush() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let _effect = {
+            let a = a.clone();
+            let seen = seen.clone();
+            rt.effect(move || seen.lock().unwrap().push(a.get()))
+        };
+        assert_eq!(*seen.lock().unwrap(), [1]);
+
+        a.set(2);
+        a.set(3);
+        assert_eq!(*seen.lock().unwrap(), [1]);
+        rt.flush_effects();
+        assert_eq!(*seen.lock().unwrap(), [1, 3]);
+
+        // Nothing changed, nothing runs.
+        rt.flush_effects();
+        assert_eq!(*seen.lock().unwrap(), [1, 3]);
+    }
+
+    #[test]
+    fn effect_runs_after_batch() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let mut b = rt.var(2);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let _effect = {
+            let (a, b) = (a.clone(), b.clone());
+            let seen = seen.clone();
+            rt.effect(move || seen.lock().unwrap().push(a.get() + b.get()))
+        };
+
+        rt.batch(|| {
+            a.set(2);
+            b.set(3);
+        });
+        assert_eq!(*seen.lock().unwrap(), [3, 5]);
+    }
+
+    #[test]
+    fn effect_is_not_rerun_if_dependency_did_not_change() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let parity = {
+            let a = a.clone();
+            rt.computed_eq(move || a.get() % 2)
+        };
+        let runs = Arc::new(Mutex::new(0));
+        let _effect = {
+            let runs = runs.clone();
+            rt.effect(move || {
+                parity.track();
+                *runs.lock().unwrap() += 1;
+            })
+        };
+
+        a.set(3);
+        rt.flush_effects();
+        assert_eq!(*runs.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn dropped_effect_does_not_run() {
+        let rt = Runtime::new();
+        let mut a = rt.var(1);
+        let runs = Arc::new(Mutex::new(0));
+        let effect = {
+            let a = a.clone();
+            let runs = runs.clone();
+            rt.effect(move || {
+                a.track();
+                *runs.lock().unwrap() += 1;
+            })
+        };
+        assert_eq!(a.readers_count(), 1);
+
+        a.set(2);
+        drop(effect);
+        assert_eq!(a.readers_count(), 0);
+        rt.flush_effects();
+        assert_eq!(*runs.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn effect_runs_for_changes_from_other_threads() {
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let _effect = {
+            let a = a.clone();
+            let seen = seen.clone();
+            rt.effect(move || seen.lock().unwrap().push(a.get()))
+        };
+
+        std::thread::spawn({
+            let mut a = a.clone();
+            move || a.set(2)
+        })
+        .join()
+        .unwrap();
+        rt.flush_effects();
+        assert_eq!(*seen.lock().unwrap(), [1, 2]);
+    }
+}
@@ -0,0 +1,236 @@
+use super::{
+    pool::{self, Permit},
+    Runtime, Value,
+};
+use std::panic;
+
+impl Runtime {
+    /// Runs `a` and `b` in parallel and returns both results.
+    ///
+    /// `a` runs on the current thread and `b` on a worker thread. The values that are read in `a`
+    /// and `b` are tracked as dependencies of the value that is currently evaluating, as if `a` and
+    /// `b` were run one after the other. Vars that are changed in `b` while a batch is open on the
+    /// current thread are committed with that batch.
+    ///
+    /// The worker threads are shared by all runtimes of the process and their number is limited to
+    /// the available parallelism, so nested `join()`s don't multiply the number of threads. If no
+    /// worker is free, `b` runs after `a` on the current thread.
+    ///
+    /// If `b` panics, the panic is propagated to the caller.
+    pub fn join<A, B>(&self, a: impl FnOnce() -> A, b: impl FnOnce() -> B + Send) -> (A, B)
+    where
+        B: Send,
+    {
+        let Some(permit) = Permit::acquire() else {
+            let a = a();
+            return (a, b());
+        };
+        let fork = self.fork();
+        let b = pool::spawn(permit, || self.run_forked(fork, b));
+        let a = a();
+        let (b, forked) = b
+            .join()
+            .unwrap_or_else(|payload| panic::resume_unwind(payload));
+        self.join_forked(forked);
+        let b = b.unwrap_or_else(|payload| panic::resume_unwind(payload));
+        (a, b)
+    }
+
+    /// Evaluates `values` in parallel and returns clones of them.
+    ///
+    /// The first value is read on the current thread, the others on worker threads, limited like
+    /// in `join()`. The values that don't get a worker are read on the current thread. The reads
+    /// are tracked in the order of `values`.
+    ///
+    /// # Panics
+    ///
+    /// Like `Value::get()`, if one of the values can't be evaluated.
+    pub fn par_get<T>(&self, values: &[Value<T>]) -> Vec<T>
+    where
+        T: Clone + Send + Sync,
+    {
+        let Some((first, rest)) = values.split_first() else {
+            return Vec::new();
+        };
+        let fork = self.fork();
+        let tasks: Vec<_> = rest
+            .iter()
+            .map(|value| {
+                let permit = Permit::acquire()?;
+                let fork = fork.clone();
+                Some(pool::spawn(permit, move || {
+                    self.run_forked(fork, || value.get())
+                }))
+            })
+            .collect();
+        let mut results = Vec::with_capacity(values.len());
+        results.push(first.get());
+        for (value, task) in rest.iter().zip(tasks) {
+            let Some(task) = task else {
+                results.push(value.get());
+                continue;
+            };
+            let (value, forked) = task
+                .join()
+                .unwrap_or_else(|payload| panic::resume_unwind(payload));
+            self.join_forked(forked);
+            results.push(value.unwrap_or_else(|payload| panic::resume_unwind(payload)));
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        map,
+        sync::{pool, tests::Late, Runtime},
+        Error,
+    };
+    use std::{collections::HashSet, sync::mpsc, thread, time::Duration};
+
+    #[test]
+    fn join_runs_in_parallel() {
+        let _exclusive = pool::exclusive();
+        let rt = Runtime::new();
+        let (to_a, from_b) = mpsc::channel();
+        let (to_b, from_a) = mpsc::channel();
+        // Each side can only finish if the other side runs at the same time.
+        let (a, b) = rt.join(
+            move || {
+                to_b.send(()).unwrap();
+                from_b.recv_timeout(Duration::from_secs(10)).is_ok()
+            },
+            move || {
+                to_a.send(()).unwrap();
+                from_a.recv_timeout(Duration::from_secs(10)).is_ok()
+            },
+        );
+        assert!(a && b);
+    }
+
+    #[test]
+    fn join_tracks_reads_in_the_caller() {
+        let _exclusive = pool::exclusive();
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let mut b = rt.var(2);
+        let a2 = map!(|a| a * 2);
+        let b2 = map!(|b| b * 2);
+        let sum = {
+            let rt = rt.clone();
+            let (a2, b2) = (a2.clone(), b2.clone());
+            rt.clone().computed(move || {
+                let (a, b) = rt.join(|| a2.get(), || b2.get());
+                a + b
+            })
+        };
+
+        assert_eq!(sum.get(), 6);
+        assert_eq!(a2.readers_count(), 1);
+        assert_eq!(b2.readers_count(), 1);
+
+        b.set(3);
+        assert!(!sum.is_valid());
+        assert_eq!(sum.get(), 8);
+    }
+
+    #[test]
+    fn par_get() {
+        let _exclusive = pool::exclusive();
+        let rt = Runtime::new();
+        let vars: Vec<_> = (0..4).map(|i| rt.var(i)).collect();
+        let squares: Vec<_> = vars.iter().map(|var| map!(|var| var * var)).collect();
+        let sum = {
+            let rt = rt.clone();
+            let squares = squares.clone();
+            rt.clone()
+                .computed(move || rt.par_get(&squares).into_iter().sum::<i32>())
+        };
+
+        assert_eq!(sum.get(), 14);
+        assert!(squares.iter().all(|square| square.readers_count() == 1));
+
+        vars[3].clone().set(4);
+        assert_eq!(sum.get(), 21);
+    }
+
+    #[test]
+    fn nested_joins_are_limited_to_the_available_parallelism() {
+        fn max_busy(rt: &Runtime, depth: usize) -> usize {
+            if depth == 0 {
+                return pool::busy();
+            }
+            let (a, b) = rt.join(|| max_busy(rt, depth - 1), || max_busy(rt, depth - 1));
+            a.max(b)
+        }
+
+        let _exclusive = pool::exclusive();
+        let max = thread::available_parallelism().unwrap().get();
+        // The limit is shared by all runtimes.
+        let busy = thread::scope(|scope| {
+            let threads: Vec<_> = (0..2)
+                .map(|_| scope.spawn(|| max_busy(&Runtime::new(), 6)))
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .max()
+        });
+        assert!(busy.unwrap() <= max);
+        assert_eq!(pool::busy(), 0);
+    }
+
+    #[test]
+    fn workers_are_reused() {
+        let _exclusive = pool::exclusive();
+        let rt = Runtime::new();
+        let max = thread::available_parallelism().unwrap().get();
+        let workers: HashSet<_> = (0..50)
+            .map(|_| rt.join(|| (), || thread::current().id()).1)
+            .collect();
+        assert!(workers.len() <= max);
+        assert!(!workers.contains(&thread::current().id()));
+    }
+
+    #[test]
+    fn changes_in_join_are_committed_with_the_batch() {
+        let _exclusive = pool::exclusive();
+        let rt = Runtime::new();
+        let a = rt.var(1);
+        let b = map!(|a| a * 2);
+        assert_eq!(b.get(), 2);
+
+        let mut a2 = a.clone();
+        rt.batch(|| {
+            rt.join(|| (), move || a2.set(2));
+            assert_eq!(a.get(), 2);
+            assert!(b.is_valid());
+        });
+        assert!(!b.is_valid());
+        assert_eq!(b.get(), 4);
+    }
+
+    #[test]
+    fn cycle_through_join_is_detected() {
+        let _exclusive = pool::exclusive();
+        let rt = Runtime::new();
+        let late: Late<Result<i32, Error>> = Late::new();
+        let a = {
+            let rt = rt.clone();
+            let late = late.clone();
+            rt.clone().computed(move || {
+                let a = late.get();
+                let ((), result) = rt.join(|| (), || a.try_get());
+                let _ = result?;
+                Ok(1)
+            })
+        };
+        late.set(a.clone());
+
+        let Err(Error::Cycle(error)) = a.get() else {
+            panic!("cycle expected");
+        };
+        assert_eq!(error.nodes(), [a.id()]);
+    }
+}
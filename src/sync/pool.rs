@@ -0,0 +1,189 @@
+//! The helper threads of `Runtime::join()` and `Runtime::par_get()`.
+//!
+//! The workers are started on demand and are shared by all runtimes of the process. Their number
+//! is limited to the available parallelism. A task is only spawned if a worker is free to run it,
+//! so tasks never wait for each other in the queue, even if they spawn and wait for tasks
+//! themselves.
+
+use std::{
+    collections::VecDeque,
+    marker::PhantomData,
+    mem,
+    num::NonZeroUsize,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError},
+    thread,
+};
+
+struct Pool {
+    /// The maximum number of workers.
+    max: usize,
+    state: Mutex<State>,
+    /// Notified when a job is queued.
+    queued: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    /// The number of workers that were started.
+    workers: usize,
+    /// The number of permits that are held. At most `workers`.
+    busy: usize,
+    jobs: VecDeque<Job>,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+fn pool() -> &'static Pool {
+    static POOL: OnceLock<Pool> = OnceLock::new();
+    POOL.get_or_init(|| Pool {
+        max: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        state: Mutex::default(),
+        queued: Condvar::new(),
+    })
+}
+
+impl Pool {
+    fn state(&self) -> MutexGuard<'_, State> {
+        // Jobs don't panic while the state is locked.
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn work(&self) {
+        let mut state = self.state();
+        loop {
+            match state.jobs.pop_front() {
+                Some(job) => {
+                    drop(state);
+                    job();
+                    state = self.state();
+                }
+                None => {
+                    state = self
+                        .queued
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner)
+                }
+            }
+        }
+    }
+}
+
+/// Reserves a free worker for one task. Released when the task finished, or when it's dropped.
+pub(crate) struct Permit(());
+
+impl Permit {
+    /// Returns `None` if all workers are busy.
+    pub fn acquire() -> Option<Permit> {
+        let pool = pool();
+        let mut state = pool.state();
+        if state.busy == pool.max {
+            return None;
+        }
+        if state.workers == state.busy {
+            thread::Builder::new()
+                .name("granularity-worker".into())
+                .spawn(move || pool.work())
+                .ok()?;
+            state.workers += 1;
+        }
+        state.busy += 1;
+        Some(Permit(()))
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        pool().state().busy -= 1;
+    }
+}
+
+/// The result of a task, set by the worker.
+struct Slot<R> {
+    result: Mutex<Option<thread::Result<R>>>,
+    done: Condvar,
+}
+
+/// A task that runs on a worker. Waits for the task to finish when it's dropped, so that the task
+/// can borrow from the spawning thread.
+pub(crate) struct Task<'a, R> {
+    slot: Arc<Slot<R>>,
+    joined: bool,
+    _borrow: PhantomData<&'a ()>,
+}
+
+/// Runs `f` on the worker that was reserved by `permit`.
+pub(crate) fn spawn<'a, R: Send + 'a>(
+    permit: Permit,
+    f: impl FnOnce() -> R + Send + 'a,
+) -> Task<'a, R> {
+    let slot = Arc::new(Slot {
+        result: Mutex::new(None),
+        done: Condvar::new(),
+    });
+    let job: Box<dyn FnOnce() + Send + 'a> = {
+        let slot = slot.clone();
+        Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            // The worker is free before the task is done, so that the spawning thread can reuse
+            // it right away.
+            drop(permit);
+            *slot.result.lock().unwrap_or_else(PoisonError::into_inner) = Some(result);
+            slot.done.notify_one();
+        })
+    };
+    // SAFETY: The task waits for the job to finish before it's dropped, so everything that the
+    // job borrows outlives it. Tasks are never leaked.
+    let job: Job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'a>, Job>(job) };
+    let pool = pool();
+    pool.state().jobs.push_back(job);
+    pool.queued.notify_one();
+    Task {
+        slot,
+        joined: false,
+        _borrow: PhantomData,
+    }
+}
+
+impl<R> Task<'_, R> {
+    /// Waits for the task to finish and returns its result, or the panic payload.
+    pub fn join(mut self) -> thread::Result<R> {
+        let result = self.wait().take().unwrap();
+        self.joined = true;
+        result
+    }
+
+    fn wait(&self) -> MutexGuard<'_, Option<thread::Result<R>>> {
+        let result = self
+            .slot
+            .result
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.slot
+            .done
+            .wait_while(result, |result| result.is_none())
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<R> Drop for Task<'_, R> {
+    fn drop(&mut self) {
+        if !self.joined {
+            drop(self.wait());
+        }
+    }
+}
+
+/// The number of workers that run a task.
+#[cfg(test)]
+pub(crate) fn busy() -> usize {
+    pool().state().busy
+}
+
+/// Serializes the tests that use the pool, so that tests that need free workers don't fail
+/// because other tests occupy them.
+#[cfg(test)]
+pub(crate) fn exclusive() -> MutexGuard<'static, ()> {
+    static EXCLUSIVE: Mutex<()> = Mutex::new(());
+    EXCLUSIVE.lock().unwrap_or_else(PoisonError::into_inner)
+}
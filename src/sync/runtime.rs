@@ -0,0 +1,554 @@
+use super::value::{AnyNode, Value};
+use crate::{
+    dot,
+    runtime::{NodeInfo, NodeLabel, Revision},
+    CycleError, Error, NodeId,
+};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashSet},
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+};
+
+/// A runtime whose values can be shared between threads.
+///
+/// Values are computed on the thread that reads them. If another thread reads a value that is
+/// evaluating, it waits until the evaluation is finished.
+#[derive(Clone)]
+pub struct Runtime(Arc<RuntimeInner>);
+
+impl Runtime {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Runtime {
+        Runtime(Arc::new(RuntimeInner::default()))
+    }
+
+    pub fn var<T>(&self, value: T) -> Value<T>
+    where
+        T: Send + Sync,
+    {
+        Value::new_var(self, value)
+    }
+
+    /// Create a var with a name that is shown in diagnostics.
+    pub fn var_named<T>(&self, name: impl Into<Arc<str>>, value: T) -> Value<T>
+    where
+        T: Send + Sync,
+    {
+        Value::new_var(self, value).named(name)
+    }
+
+    pub fn computed<T>(&self, compute: impl FnMut() -> T + Send + 'static) -> Value<T>
+    where
+        T: Send + Sync,
+    {
+        Value::new_computed(self, compute)
+    }
+
+    /// Create a computed value that may fail.
+    ///
+    /// An error is cached like a value and returned from `Value::try_get()` until one of the
+    /// dependencies changes. Inside `compute`, errors of dependencies can be propagated by using
+    /// `Value::try_get()` and `?`.
+    pub fn try_computed<T>(
+        &self,
+        compute: impl FnMut() -> Result<T, Error> + Send + 'static,
+    ) -> Value<T>
+    where
+        T: Send + Sync,
+    {
+        Value::new_try_computed(self, compute)
+    }
+
+    /// Create a var that does not invalidate its readers when it is set to a value that is equal
+    /// to the current one.
+    pub fn var_eq<T>(&self, value: T) -> Value<T>
+    where
+        T: PartialEq + Send + Sync,
+    {
+        Value::new_var(self, value).with_eq()
+    }
+
+    /// Create a computed value that compares a recomputed result to the previous one.
+    ///
+    /// If they are equal, readers of this value are considered to be up to date and are not
+    /// recomputed, unless some of their other dependencies changed.
+    pub fn computed_eq<T>(&self, compute: impl FnMut() -> T + Send + 'static) -> Value<T>
+    where
+        T: PartialEq + Send + Sync,
+    {
+        Value::new_computed(self, compute).with_eq()
+    }
+
+    /// Create a computed value that returns `fallback` to reads that are part of a dependency
+    /// cycle, instead of failing with a `CycleError`.
+    pub fn computed_with_cycle_fallback<T>(
+        &self,
+        compute: impl FnMut() -> T + Send + 'static,
+        fallback: T,
+    ) -> Value<T>
+    where
+        T: Clone + Send + Sync,
+    {
+        Value::new_computed(self, compute).with_cycle_fallback(fallback)
+    }
+
+    /// Create a computed value that receives its previous value when it is recomputed.
+    ///
+    /// See `crate::Runtime::computed_with_prev()`. The previous value is cloned if there are still
+    /// references to it.
+    pub fn computed_with_prev<T>(
+        &self,
+        compute: impl FnMut(Option<T>) -> T + Send + 'static,
+    ) -> Value<T>
+    where
+        T: Clone + Send + Sync,
+    {
+        Value::new_computed_with_prev(self, compute)
+    }
+
+    /// Create a computed value that accumulates its results.
+    ///
+    /// See `crate::Runtime::fold()`.
+    pub fn fold<T>(&self, init: T, mut f: impl FnMut(T) -> T + Send + 'static) -> Value<T>
+    where
+        T: Clone + Send + Sync,
+    {
+        self.computed_with_prev(move |acc| f(acc.unwrap_or_else(|| init.clone())))
+    }
+
+    pub fn memo<K, T>(
+        &self,
+        key: impl Fn() -> K + Send + 'static,
+        mut compute: impl FnMut(&K) -> T + Send + 'static,
+    ) -> Value<T>
+    where
+        K: PartialEq + Send + 'static,
+        T: Clone + Send + Sync,
+    {
+        let mut prev: Option<(K, T)> = None;
+        Value::new_computed(self, move || {
+            let key = key();
+            if let Some((prev_key, prev_value)) = &prev {
+                if key == *prev_key {
+                    return prev_value.clone();
+                }
+            }
+            let value = compute(&key);
+            prev = Some((key, value.clone()));
+            value
+        })
+    }
+
+    /// Enables or disables the recording of invalidation causes.
+    ///
+    /// See `crate::Runtime::trace_invalidations()`.
+    pub fn trace_invalidations(&self, enabled: bool) {
+        self.0.trace_invalidations.store(enabled, Ordering::Relaxed);
+    }
+
+    pub(crate) fn traces_invalidations(&self) -> bool {
+        self.0.trace_invalidations.load(Ordering::Relaxed)
+    }
+
+    /// Run `f` as a batch.
+    ///
+    /// Vars that are changed inside the batch are updated immediately, but the invalidation of
+    /// their readers is postponed until the outermost batch commits. Batches are local to the
+    /// current thread, changes from other threads are not postponed.
+    ///
+    /// If `f` panics, the batch is committed while unwinding, so that no changes get lost. The
+    /// effects are not run in this case.
+    pub fn batch<R>(&self, f: impl FnOnce() -> R) -> R {
+        if self.is_batching() {
+            return f();
+        }
+
+        struct Batch<'a>(&'a Runtime);
+        impl Drop for Batch<'_> {
+            fn drop(&mut self) {
+                let pending = BATCHES.with(|batches| {
+                    let mut batches = batches.borrow_mut();
+                    let index = batches
+                        .iter()
+                        .rposition(|batch| batch.runtime == self.0.key())
+                        .unwrap();
+                    batches.remove(index).pending
+                });
+                self.0.commit(pending);
+            }
+        }
+
+        BATCHES.with(|batches| {
+            batches.borrow_mut().push(PendingBatch {
+                runtime: self.key(),
+                pending: BTreeMap::new(),
+            })
+        });
+        let _batch = Batch(self);
+        f()
+    }
+
+    /// Re-runs all effects whose dependencies changed.
+    ///
+    /// # Panics
+    ///
+    /// If an effect failed. The remaining effects are run before.
+    pub fn flush_effects(&self) {
+        let mut first_error = None;
+        loop {
+            let dirty = mem::take(&mut *self.0.dirty_effects.lock().unwrap());
+            if dirty.is_empty() {
+                break;
+            }
+            for effect in dirty.iter().filter_map(Weak::upgrade) {
+                if let Err(e) = effect.ensure_valid() {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = first_error {
+            panic!("{e}");
+        }
+    }
+
+    /// Exports the current dependency graph in the Graphviz DOT format.
+    ///
+    /// See `crate::Runtime::export_dot()`.
+    pub fn export_dot(&self) -> String {
+        let nodes: Vec<NodeInfo> = self.nodes().iter().map(|node| node.info()).collect();
+        dot::to_dot(&nodes)
+    }
+
+    fn commit(&self, pending: BTreeMap<NodeId, Arc<dyn AnyNode>>) {
+        if pending.is_empty() {
+            return;
+        }
+        // All changes of a batch share the same revision.
+        let revision = self.new_revision();
+        for node in pending.values() {
+            node.changed(revision);
+        }
+
+        // Don't run user code while unwinding from a panic inside the batch. The effects stay
+        // scheduled until the next flush.
+        if !thread::panicking() {
+            self.flush_effects();
+        }
+    }
+
+    pub(crate) fn is_batching(&self) -> bool {
+        BATCHES.with(|batches| {
+            batches
+                .borrow()
+                .iter()
+                .any(|batch| batch.runtime == self.key())
+        })
+    }
+
+    /// Postpones the invalidation of `node` until the current batch commits.
+    pub(crate) fn defer_invalidation(&self, node: Arc<dyn AnyNode>) {
+        BATCHES.with(|batches| {
+            let mut batches = batches.borrow_mut();
+            let batch = batches
+                .iter_mut()
+                .find(|batch| batch.runtime == self.key())
+                .expect("Not batching");
+            batch.pending.insert(node.id(), node);
+        })
+    }
+
+    /// Schedules an effect to be re-run in the next `flush_effects()`.
+    pub(crate) fn schedule_effect(&self, effect: Weak<dyn AnyNode>) {
+        self.0.dirty_effects.lock().unwrap().push(effect);
+    }
+
+    /// Evaluates `f` with a frame for `node` on top of the evaluation stack of this thread.
+    ///
+    /// Returns the result, or the panic payload, and the nodes that were read.
+    pub(crate) fn eval<R>(
+        &self,
+        node: NodeLabel,
+        f: impl FnOnce() -> R,
+    ) -> (thread::Result<R>, Trace) {
+        // Pops the frame even if `f` panics.
+        struct Eval;
+        impl Drop for Eval {
+            fn drop(&mut self) {
+                STACK.with(|stack| stack.borrow_mut().pop());
+            }
+        }
+
+        STACK.with(|stack| {
+            stack.borrow_mut().push(Frame {
+                runtime: self.key(),
+                node,
+                trace: Vec::new(),
+                read: HashSet::new(),
+                tracking: true,
+            })
+        });
+        let eval = Eval;
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        let trace =
+            STACK.with(|stack| mem::take(&mut stack.borrow_mut().last_mut().unwrap().trace));
+        drop(eval);
+        (result, trace)
+    }
+
+    /// Copies the evaluation stack and the open batches of this thread, so that other threads can
+    /// read values on behalf of the value that is currently evaluating, and change vars on behalf
+    /// of the batches.
+    pub(crate) fn fork(&self) -> Fork {
+        Fork {
+            stack: STACK.with(|stack| stack.borrow().iter().map(Frame::fork).collect()),
+            batches: BATCHES
+                .with(|batches| batches.borrow().iter().map(|batch| batch.runtime).collect()),
+        }
+    }
+
+    /// Runs `f` with the evaluation stack and the batches of `fork`, and returns its result, or
+    /// the panic payload, together with the nodes it read and changed.
+    ///
+    /// Reads of values that are on the forked stack are detected as cycles. Pass the returned
+    /// `Forked` to `join_forked()` on the thread that forked.
+    pub(crate) fn run_forked<R>(
+        &self,
+        fork: Fork,
+        f: impl FnOnce() -> R,
+    ) -> (thread::Result<R>, Forked) {
+        // Restores the stack and the batches of this thread even if `f` panics.
+        struct Restore(Vec<Frame>, Vec<PendingBatch>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                STACK.with(|stack| mem::swap(&mut *stack.borrow_mut(), &mut self.0));
+                BATCHES.with(|batches| mem::swap(&mut *batches.borrow_mut(), &mut self.1));
+            }
+        }
+
+        let forked_batches = fork
+            .batches
+            .into_iter()
+            .map(|runtime| PendingBatch {
+                runtime,
+                pending: BTreeMap::new(),
+            })
+            .collect();
+        let restore = Restore(
+            STACK.with(|stack| mem::replace(&mut *stack.borrow_mut(), fork.stack)),
+            BATCHES.with(|batches| mem::replace(&mut *batches.borrow_mut(), forked_batches)),
+        );
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        let trace = STACK.with(|stack| {
+            stack
+                .borrow_mut()
+                .last_mut()
+                .filter(|frame| frame.runtime == self.key())
+                .map(|frame| mem::take(&mut frame.trace))
+                .unwrap_or_default()
+        });
+        let batches = BATCHES.with(|batches| mem::take(&mut *batches.borrow_mut()));
+        drop(restore);
+        (result, Forked { trace, batches })
+    }
+
+    /// Records the reads that happened in `run_forked()` on another thread in the value that is
+    /// currently evaluating on this thread, and adds the changes to the batches of this thread.
+    pub(crate) fn join_forked(&self, forked: Forked) {
+        for node in forked.trace {
+            self.track_read(node);
+        }
+        BATCHES.with(|batches| {
+            let mut batches = batches.borrow_mut();
+            for forked in forked.batches {
+                let batch = batches
+                    .iter_mut()
+                    .rfind(|batch| batch.runtime == forked.runtime)
+                    .expect("Forked batch is not open");
+                batch.pending.extend(forked.pending);
+            }
+        })
+    }
+
+    /// Records a read of `node` in the value that is currently evaluating on this thread.
+    pub(crate) fn track_read(&self, node: Arc<dyn AnyNode>) {
+        STACK.with(|stack| {
+            if let Some(frame) = stack.borrow_mut().last_mut() {
+                if frame.runtime == self.key() && frame.tracking && frame.read.insert(node.id()) {
+                    frame.trace.push(node);
+                }
+            }
+        })
+    }
+
+    /// Runs `f` without tracking the values it reads as dependencies of the value that is
+    /// currently evaluating on this thread.
+    ///
+    /// Values that are evaluated inside `f` still track their own dependencies.
+    pub fn untrack<R>(&self, f: impl FnOnce() -> R) -> R {
+        // Restores the tracking state even if `f` panics.
+        struct Restore(bool);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                STACK.with(|stack| {
+                    if let Some(frame) = stack.borrow_mut().last_mut() {
+                        frame.tracking = self.0;
+                    }
+                })
+            }
+        }
+
+        let tracking = STACK.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            let frame = stack
+                .last_mut()
+                .filter(|frame| frame.runtime == self.key())?;
+            Some(mem::replace(&mut frame.tracking, false))
+        });
+        let Some(tracking) = tracking else {
+            return f();
+        };
+        let _restore = Restore(tracking);
+        f()
+    }
+
+    /// Returns `true` if a value is evaluating on this thread.
+    pub(crate) fn is_evaluating(&self) -> bool {
+        STACK.with(|stack| {
+            stack
+                .borrow()
+                .last()
+                .is_some_and(|frame| frame.runtime == self.key())
+        })
+    }
+
+    /// Returns the cycle if `node` is already being evaluated on this thread.
+    pub(crate) fn detect_cycle(&self, node: NodeId) -> Option<CycleError> {
+        STACK.with(|stack| {
+            let stack = stack.borrow();
+            let start = stack
+                .iter()
+                .rposition(|frame| frame.runtime == self.key() && frame.node.id == node)?;
+            let nodes = stack[start..]
+                .iter()
+                .map(|frame| frame.node.clone())
+                .collect();
+            Some(CycleError::new(nodes))
+        })
+    }
+
+    pub(crate) fn register(&self, id: NodeId, node: Weak<dyn AnyNode>) {
+        self.0.nodes.lock().unwrap().insert(id, node);
+    }
+
+    pub(crate) fn unregister(&self, id: NodeId) {
+        self.0.nodes.lock().unwrap().remove(&id);
+    }
+
+    /// All nodes that are alive.
+    pub(crate) fn nodes(&self) -> Vec<Arc<dyn AnyNode>> {
+        let nodes = self.0.nodes.lock().unwrap();
+        nodes.values().filter_map(Weak::upgrade).collect()
+    }
+
+    pub(crate) fn new_node_id(&self) -> NodeId {
+        NodeId(self.0.next_node_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub(crate) fn revision(&self) -> Revision {
+        self.0.revision.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn new_revision(&self) -> Revision {
+        self.0.revision.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn key(&self) -> RuntimeKey {
+        Arc::as_ptr(&self.0) as RuntimeKey
+    }
+}
+
+#[derive(Default)]
+struct RuntimeInner {
+    next_node_id: AtomicU64,
+    /// The current revision. Incremented every time vars are changed.
+    revision: AtomicU64,
+    /// Effects that were invalidated and need to be re-run. Effects that were dropped in the
+    /// meantime can't be upgraded anymore and are skipped.
+    dirty_effects: Mutex<Vec<Weak<dyn AnyNode>>>,
+    /// All nodes of this runtime.
+    nodes: Mutex<BTreeMap<NodeId, Weak<dyn AnyNode>>>,
+    /// Set if invalidation causes are recorded.
+    trace_invalidations: AtomicBool,
+}
+
+/// Identifies a runtime in the thread local state.
+type RuntimeKey = usize;
+
+/// The nodes a value read in its last evaluation, in the order they were first read.
+pub(crate) type Trace = Vec<Arc<dyn AnyNode>>;
+
+/// A value that is evaluating on the current thread.
+struct Frame {
+    runtime: RuntimeKey,
+    node: NodeLabel,
+    /// The nodes read so far.
+    trace: Trace,
+    /// The ids of the nodes in `trace`, so that repeated reads are recorded only once.
+    read: HashSet<NodeId>,
+    /// Cleared inside `Runtime::untrack()`.
+    tracking: bool,
+}
+
+impl Frame {
+    fn fork(&self) -> Frame {
+        Frame {
+            runtime: self.runtime,
+            node: self.node.clone(),
+            trace: Vec::new(),
+            read: HashSet::new(),
+            tracking: self.tracking,
+        }
+    }
+}
+
+/// A copy of an evaluation stack without the traces, and the runtimes of the open batches.
+pub(crate) struct Fork {
+    stack: Vec<Frame>,
+    batches: Vec<RuntimeKey>,
+}
+
+impl Clone for Fork {
+    fn clone(&self) -> Self {
+        Fork {
+            stack: self.stack.iter().map(Frame::fork).collect(),
+            batches: self.batches.clone(),
+        }
+    }
+}
+
+/// The nodes that were read and changed on another thread in `Runtime::run_forked()`.
+pub(crate) struct Forked {
+    trace: Trace,
+    batches: Vec<PendingBatch>,
+}
+
+/// A batch that is open on the current thread.
+struct PendingBatch {
+    runtime: RuntimeKey,
+    /// Nodes that were changed inside the batch and need to be invalidated when it commits.
+    pending: BTreeMap<NodeId, Arc<dyn AnyNode>>,
+}
+
+thread_local! {
+    /// The evaluation stack of the current thread. The last frame is the currently evaluating
+    /// value.
+    static STACK: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+    static BATCHES: RefCell<Vec<PendingBatch>> = const { RefCell::new(Vec::new()) };
+}
@@ -0,0 +1,890 @@
+use super::runtime::{Runtime, Trace};
+use crate::{
+    runtime::{NodeInfo, NodeKind, NodeLabel, NodeState, Revision},
+    Error, InvalidationCause, NodeId, PoisonError,
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    panic::{self, Location},
+    sync::{Arc, Condvar, Mutex, MutexGuard, Weak},
+    thread::{self, ThreadId},
+};
+use Primitive::*;
+
+/// A value that can be shared between threads.
+///
+/// Values are stored in an `Arc`, references to them can be kept while other threads change or
+/// recompute the value.
+pub struct Value<T: 'static> {
+    runtime: Runtime,
+    inner: Arc<Node<T>>,
+}
+
+impl<T> Clone for Value<T> {
+    fn clone(&self) -> Self {
+        Self {
+            runtime: self.runtime.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Value<T>
+where
+    T: Send + Sync + 'static,
+{
+    pub(crate) fn new_var(runtime: &Runtime, value: T) -> Self {
+        Self::new(runtime, false, Var(Arc::new(value)))
+    }
+
+    pub(crate) fn new_computed(
+        runtime: &Runtime,
+        mut compute: impl FnMut() -> T + Send + 'static,
+    ) -> Self {
+        Self::new_try_computed(runtime, move || Ok(compute()))
+    }
+
+    pub(crate) fn new_try_computed(
+        runtime: &Runtime,
+        mut compute: impl FnMut() -> Result<T, Error> + Send + 'static,
+    ) -> Self {
+        Self::new(
+            runtime,
+            false,
+            Primitive::computed(Box::new(move |_| compute())),
+        )
+    }
+
+    pub(crate) fn new_computed_with_prev(
+        runtime: &Runtime,
+        mut compute: impl FnMut(Option<T>) -> T + Send + 'static,
+    ) -> Self
+    where
+        T: Clone,
+    {
+        let compute = move |previous: &mut Option<Arc<T>>| {
+            Ok(compute(previous.take().map(Arc::unwrap_or_clone)))
+        };
+        Self::new(runtime, false, Primitive::computed(Box::new(compute)))
+    }
+
+    pub(crate) fn new_effect(
+        runtime: &Runtime,
+        mut run: impl FnMut() -> T + Send + 'static,
+    ) -> Self {
+        Self::new(
+            runtime,
+            true,
+            Primitive::computed(Box::new(move |_| Ok(run()))),
+        )
+    }
+
+    fn new(runtime: &Runtime, effect: bool, primitive: Primitive<T>) -> Self {
+        let changed_at = match primitive {
+            Var(_) => runtime.revision(),
+            Computed { .. } => 0,
+        };
+        let inner = Arc::new_cyclic(|this| Node {
+            id: runtime.new_node_id(),
+            runtime: runtime.clone(),
+            this: this.clone(),
+            readers: Default::default(),
+            state: Mutex::new(State {
+                name: None,
+                changed_at,
+                eq: None,
+                effect,
+                debug: None,
+                cycle_fallback: None,
+                change_location: None,
+                write_back: None,
+                invalidation_cause: None,
+                primitive,
+            }),
+            evaluated: Condvar::new(),
+        });
+        let weak: Weak<dyn AnyNode> = Arc::downgrade(&inner) as _;
+        runtime.register(inner.id, weak);
+        Self {
+            runtime: runtime.clone(),
+            inner,
+        }
+    }
+
+    /// Compare new values to the current ones and don't invalidate readers when they are equal.
+    pub(crate) fn with_eq(self) -> Self
+    where
+        T: PartialEq,
+    {
+        self.inner.state().eq = Some(T::eq);
+        self
+    }
+
+    /// Make the value's `Debug` output available to diagnostics, like `Runtime::export_dot()`.
+    pub fn with_debug(self) -> Self
+    where
+        T: fmt::Debug,
+    {
+        self.inner.state().debug = Some(T::fmt);
+        self
+    }
+
+    /// Return `fallback` to reads of this value that happen while the value is evaluating.
+    pub(crate) fn with_cycle_fallback(self, fallback: T) -> Self {
+        self.inner.state().cycle_fallback = Some(Arc::new(fallback));
+        self
+    }
+
+    /// Sets the name that is shown in diagnostics.
+    pub fn named(self, name: impl Into<Arc<str>>) -> Self {
+        self.inner.state().name = Some(name.into());
+        self
+    }
+
+    /// The id of the node in the runtime.
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    /// The name set with `named()`.
+    pub fn name(&self) -> Option<Arc<str>> {
+        self.inner.state().name.clone()
+    }
+
+    /// If needed, evaluates the value, then clones it and returns it.
+    pub fn get(&self) -> T
+    where
+        T: Clone,
+    {
+        (*self.get_ref()).clone()
+    }
+
+    /// If needed, evaluates the value and returns a reference to it.
+    ///
+    /// # Panics
+    ///
+    /// If the value is part of a dependency cycle, or its evaluation panicked or failed.
+    pub fn get_ref(&self) -> Arc<T> {
+        self.try_get_ref().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like `get()`, but the read is not tracked as a dependency of the value that is currently
+    /// evaluating. See `Runtime::untrack()`.
+    pub fn get_untracked(&self) -> T
+    where
+        T: Clone,
+    {
+        self.runtime.untrack(|| self.get())
+    }
+
+    /// Like `get_ref()`, but the read is not tracked as a dependency of the value that is
+    /// currently evaluating. See `Runtime::untrack()`.
+    pub fn peek(&self) -> Arc<T> {
+        self.runtime.untrack(|| self.get_ref())
+    }
+
+    /// If needed, evaluates the value, then clones it and returns it, or returns the error that
+    /// prevented the evaluation.
+    pub fn try_get(&self) -> Result<T, Error>
+    where
+        T: Clone,
+    {
+        self.try_get_ref().map(|value| (*value).clone())
+    }
+
+    /// If needed, evaluates the value and returns a reference to it, or returns the error that
+    /// prevented the evaluation.
+    pub fn try_get_ref(&self) -> Result<Arc<T>, Error> {
+        match self.ensure_valid_and_track_read() {
+            Err(Error::Cycle(cycle)) => self
+                .inner
+                .state()
+                .cycle_fallback
+                .clone()
+                .ok_or(Error::Cycle(cycle)),
+            result => result,
+        }
+    }
+
+    /// Track the value for receiving change notifications when it changes.
+    pub fn track(&self) {
+        self.ensure_valid_and_track_read()
+            .unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    /// Makes sure the value is evaluated then takes it out and invalidates it.
+    ///
+    /// The value is cloned if other threads still hold a reference to it.
+    ///
+    /// This can't be called inside a evaluation context.
+    #[track_caller]
+    pub fn take(&mut self) -> T
+    where
+        T: Clone,
+    {
+        debug_assert!(!self.runtime.is_evaluating());
+        let value = self.inner.evaluate().unwrap_or_else(|e| panic!("{e}"));
+        {
+            let mut state = self.inner.state();
+            match &mut state.primitive {
+                Var(_) => panic!("Cannot take a var"),
+                Computed { value, .. } => *value = None,
+            }
+            state.record_change_location(&self.runtime, Location::caller());
+        }
+        // Readers that verify in the same revision would not see that the recomputed value
+        // changed.
+        self.runtime.new_revision();
+        self.inner.invalidate_readers();
+        Arc::unwrap_or_clone(value)
+    }
+
+    /// Sets the value of a var.
+    ///
+    /// If the var was created with `Runtime::var_eq` and `value` is equal to the current value,
+    /// the var stays unchanged and its readers are not invalidated.
+    #[track_caller]
+    pub fn set(&mut self, value: T) {
+        self.update_lens();
+        if self.inner.state().is_equal_to(&value) {
+            return;
+        }
+        self.change(Location::caller(), |current| *current = Arc::new(value));
+    }
+
+    /// Sets the value of a var, but only if it differs from the current value.
+    #[track_caller]
+    pub fn set_if_changed(&mut self, value: T)
+    where
+        T: PartialEq,
+    {
+        self.update_lens();
+        if self.inner.state().primitive.value() == Some(&value) {
+            return;
+        }
+        self.change(Location::caller(), |current| *current = Arc::new(value));
+    }
+
+    /// Changes the value of a var by applying `f` to it and invalidates all its readers.
+    ///
+    /// The value is cloned before if other threads still hold a reference to it.
+    ///
+    /// Inside a batch, the invalidation is postponed until the batch commits.
+    ///
+    /// Changes to a lens are written back into its source, see `lens()`.
+    #[track_caller]
+    pub fn apply(&mut self, f: impl FnOnce(T) -> T)
+    where
+        T: Clone,
+    {
+        self.change(Location::caller(), |current| {
+            replace_with::replace_with_or_abort(Arc::make_mut(current), f)
+        });
+    }
+
+    fn change(&self, location: &'static Location<'static>, f: impl FnOnce(&mut Arc<T>)) {
+        let write_back = self.inner.state().write_back.clone();
+        if let Some(write_back) = write_back {
+            let mut value = self.inner.evaluate().unwrap_or_else(|e| panic!("{e}"));
+            f(&mut value);
+            write_back(value, location);
+            return;
+        }
+        {
+            let mut state = self.inner.state();
+            match &mut state.primitive {
+                Var(value) => f(value),
+                Computed { .. } => panic!("Cannot set a computed value"),
+            }
+            state.record_change_location(&self.runtime, location);
+        }
+        if self.runtime.is_batching() {
+            self.runtime.defer_invalidation(self.inner.clone());
+        } else {
+            self.inner.changed(self.runtime.new_revision());
+        }
+    }
+
+    /// Creates a value that focuses on a part of this value.
+    ///
+    /// See `crate::Value::lens()`.
+    pub fn lens<U>(
+        &self,
+        get: impl Fn(&T) -> &U + Send + Sync + 'static,
+        set: impl Fn(&mut T, U) + Send + Sync + 'static,
+    ) -> Value<U>
+    where
+        T: Clone,
+        U: Clone + Send + Sync,
+    {
+        let lens = {
+            let source = self.clone();
+            self.runtime
+                .computed(move || get(&source.get_ref()).clone())
+        };
+        let source = self.clone();
+        lens.inner.state().write_back = Some(Arc::new(move |part, location| {
+            source.change(location, |value| {
+                set(Arc::make_mut(value), Arc::unwrap_or_clone(part))
+            })
+        }));
+        lens
+    }
+
+    /// Creates a projection of a field of this value.
+    ///
+    /// See `crate::Value::project()`.
+    pub fn project<U>(
+        &self,
+        get: impl Fn(&T) -> &U + Send + Sync + 'static,
+        set: impl Fn(&mut T, U) + Send + Sync + 'static,
+    ) -> Value<U>
+    where
+        T: Clone,
+        U: Clone + PartialEq + Send + Sync,
+    {
+        self.lens(get, set).with_eq()
+    }
+
+    /// Brings a lens up to date, so that new values are compared against the current value of
+    /// its source.
+    fn update_lens(&self) {
+        if self.inner.state().write_back.is_some() {
+            let _ = self.inner.evaluate();
+        }
+    }
+
+    /// The cause of the last invalidation of this value, if invalidation tracing is enabled with
+    /// `Runtime::trace_invalidations()`.
+    pub fn last_invalidation_cause(&self) -> Option<InvalidationCause> {
+        self.inner.state().invalidation_cause.clone()
+    }
+
+    pub fn runtime(&self) -> Runtime {
+        self.runtime.clone()
+    }
+
+    /// Brings the value up to date without tracking the read.
+    pub(crate) fn update(&self) -> Result<(), Error> {
+        self.inner.ensure_valid()
+    }
+
+    fn ensure_valid_and_track_read(&self) -> Result<Arc<T>, Error> {
+        let result = self.inner.evaluate();
+        // Reads that close a cycle are not tracked, the value would depend on itself otherwise.
+        if !matches!(result, Err(Error::Cycle(_))) {
+            self.runtime.track_read(self.inner.clone());
+        }
+        result
+    }
+
+    #[cfg(test)]
+    pub fn is_valid(&self) -> bool {
+        self.inner.state().is_valid()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn readers_count(&self) -> usize {
+        self.inner.readers.lock().unwrap().len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn trace_len(&self) -> usize {
+        match &self.inner.state().primitive {
+            Var(_) => 0,
+            Computed { trace, .. } => trace.len(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Value<T>
+where
+    T: fmt::Debug + Send + Sync + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.inner.state();
+        let (kind, node_state) = state.kind_and_state();
+        let mut debug = f.debug_struct("Value");
+        debug
+            .field("id", &self.inner.id)
+            .field("name", &state.name)
+            .field("kind", &format_args!("{kind}"))
+            .field("state", &format_args!("{node_state}"));
+        match &state.primitive {
+            Var(value)
+            | Computed {
+                value: Some(Ok(value)),
+                ..
+            } => debug.field("value", value),
+            Computed {
+                value: Some(Err(error)),
+                ..
+            } => debug.field("error", error),
+            Computed { value: None, .. } => &mut debug,
+        };
+        debug.finish()
+    }
+}
+
+impl<T> fmt::Display for Value<T>
+where
+    T: Send + Sync + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.inner.state();
+        let (kind, node_state) = state.kind_and_state();
+        write!(f, "{}: {kind}, {node_state}", state.label(self.inner.id))
+    }
+}
+
+/// A type erased node.
+pub(crate) trait AnyNode: Send + Sync {
+    fn id(&self) -> NodeId;
+    /// Records that the value of this node changed in `revision` and invalidates all its readers.
+    fn changed(&self, revision: Revision);
+    /// Marks this node as possibly outdated and propagates this to all its readers.
+    fn invalidate(&self, cause: Option<&mut InvalidationCause>);
+    /// Brings the value up to date.
+    fn ensure_valid(&self) -> Result<(), Error>;
+    /// Brings the value up to date and returns the revision it last changed in. Returns `None` if
+    /// the value is evaluating or verifying its dependencies on this thread, which means that it
+    /// is part of a cycle.
+    fn update(&self) -> Option<Revision>;
+    fn add_reader(&self, reader: NodeId, node: Weak<dyn AnyNode>);
+    fn remove_reader(&self, reader: NodeId);
+    fn info(&self) -> NodeInfo;
+}
+
+struct Node<T: 'static> {
+    id: NodeId,
+    runtime: Runtime,
+    this: Weak<Node<T>>,
+    /// The nodes that read this node in their last evaluation.
+    readers: Mutex<BTreeMap<NodeId, Weak<dyn AnyNode>>>,
+    state: Mutex<State<T>>,
+    /// Notified when an evaluation finished.
+    evaluated: Condvar,
+}
+
+struct State<T: 'static> {
+    name: Option<Arc<str>>,
+    /// The revision in which the value changed the last time.
+    changed_at: Revision,
+    eq: Option<fn(&T, &T) -> bool>,
+    /// Set if this is the value of an effect.
+    effect: bool,
+    debug: Option<fn(&T, &mut fmt::Formatter<'_>) -> fmt::Result>,
+    cycle_fallback: Option<Arc<T>>,
+    /// Where the value was changed, if invalidation tracing is enabled. Taken when the readers
+    /// are invalidated.
+    change_location: Option<&'static Location<'static>>,
+    /// Why the value was invalidated the last time, if invalidation tracing is enabled.
+    invalidation_cause: Option<InvalidationCause>,
+    /// Set for lenses. Writes a changed value back into the source of the lens.
+    write_back: Option<WriteBack<T>>,
+    primitive: Primitive<T>,
+}
+
+type WriteBack<T> = Arc<dyn Fn(Arc<T>, &'static Location<'static>) + Send + Sync>;
+
+/// Computes the value of a computed. Receives the previous value, which is still set after the
+/// call if `compute` did not take it.
+type Compute<T> = Box<dyn FnMut(&mut Option<Arc<T>>) -> Result<T, Error> + Send>;
+
+enum Primitive<T: 'static> {
+    Var(Arc<T>),
+    Computed {
+        value: Option<Result<Arc<T>, Error>>,
+        /// Taken out while the value is computed.
+        compute: Option<Compute<T>>,
+        /// The thread that verifies or computes the value. Other threads wait until it's done.
+        evaluating: Option<ThreadId>,
+        trace: Trace,
+        // The revision in which the value was computed or verified the last time.
+        verified_at: Revision,
+        // Set when a dependency might have changed.
+        outdated: bool,
+    },
+}
+
+impl<T> Primitive<T> {
+    fn computed(compute: Compute<T>) -> Self {
+        Computed {
+            value: None,
+            compute: Some(compute),
+            evaluating: None,
+            trace: Vec::new(),
+            verified_at: 0,
+            outdated: false,
+        }
+    }
+
+    fn value(&self) -> Option<&T> {
+        match self {
+            Var(value) => Some(value),
+            Computed { value, .. } => value.as_ref()?.as_deref().ok(),
+        }
+    }
+}
+
+impl<T> State<T> {
+    /// Returns `true` if this value compares for equality and is equal to `value`.
+    fn is_equal_to(&self, value: &T) -> bool {
+        match (self.eq, self.primitive.value()) {
+            (Some(eq), Some(current)) => eq(current, value),
+            _ => false,
+        }
+    }
+
+    #[cfg(test)]
+    fn is_valid(&self) -> bool {
+        match self.primitive {
+            Var(_) => true,
+            Computed {
+                ref value,
+                outdated,
+                ..
+            } => value.is_some() && !outdated,
+        }
+    }
+
+    /// Remembers `location` as the cause of the next invalidation.
+    fn record_change_location(&mut self, runtime: &Runtime, location: &'static Location<'static>) {
+        if runtime.traces_invalidations() {
+            self.change_location = Some(location);
+        }
+    }
+
+    fn kind_and_state(&self) -> (NodeKind, NodeState) {
+        match self.primitive {
+            Var(_) => (NodeKind::Var, NodeState::Valid),
+            Computed {
+                ref value,
+                outdated,
+                ..
+            } => {
+                let kind = match self.effect {
+                    true => NodeKind::Effect,
+                    false => NodeKind::Computed,
+                };
+                let state = match value {
+                    None => NodeState::Unevaluated,
+                    Some(Err(_)) => NodeState::Failed,
+                    Some(Ok(_)) if outdated => NodeState::Outdated,
+                    Some(Ok(_)) => NodeState::Valid,
+                };
+                (kind, state)
+            }
+        }
+    }
+
+    fn label(&self, id: NodeId) -> NodeLabel {
+        NodeLabel {
+            id,
+            name: self.name.clone(),
+        }
+    }
+}
+
+impl<T> Node<T>
+where
+    T: Send + Sync + 'static,
+{
+    fn state(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap()
+    }
+
+    /// Brings the value up to date and returns it.
+    ///
+    /// If another thread is evaluating the value, waits until it's done.
+    fn evaluate(&self) -> Result<Arc<T>, Error> {
+        let mut state = self.state();
+        loop {
+            match &state.primitive {
+                Var(value) => return Ok(value.clone()),
+                Computed {
+                    evaluating: Some(thread),
+                    ..
+                } => {
+                    // The stack of this thread also contains the frames of the values that wait
+                    // for it in `Runtime::join()`.
+                    if let Some(cycle) = self.runtime.detect_cycle(self.id) {
+                        return Err(Error::Cycle(cycle));
+                    }
+                    debug_assert_ne!(*thread, thread::current().id());
+                    state = self.evaluated.wait(state).unwrap();
+                }
+                Computed {
+                    value: Some(value),
+                    outdated: false,
+                    ..
+                } => return value.clone(),
+                Computed { .. } => break,
+            }
+        }
+
+        let revision = self.runtime.revision();
+        let label = state.label(self.id);
+        let Computed {
+            ref value,
+            ref mut evaluating,
+            ref trace,
+            verified_at,
+            ..
+        } = state.primitive
+        else {
+            unreachable!()
+        };
+        *evaluating = Some(thread::current().id());
+        let verify = value.is_some().then(|| trace.clone());
+        drop(state);
+        let evaluation = Evaluation(self);
+
+        if let Some(trace) = verify {
+            let (changed, _) = self
+                .runtime
+                .eval(label.clone(), || dependencies_changed(&trace, verified_at));
+            if !changed.unwrap_or_else(|payload| panic::resume_unwind(payload)) {
+                let mut state = self.state();
+                let Computed {
+                    ref value,
+                    ref mut verified_at,
+                    ref mut outdated,
+                    ..
+                } = state.primitive
+                else {
+                    unreachable!()
+                };
+                let result = value.clone().unwrap();
+                *verified_at = revision;
+                // Vars might have been changed while verifying.
+                *outdated = self.runtime.revision() != revision;
+                drop(state);
+                drop(evaluation);
+                return result;
+            }
+        }
+
+        let (mut compute, previous_trace, previous) = {
+            let mut state = self.state();
+            let Computed {
+                ref mut value,
+                ref mut compute,
+                ref mut trace,
+                ..
+            } = state.primitive
+            else {
+                unreachable!()
+            };
+            (compute.take().unwrap(), std::mem::take(trace), value.take())
+        };
+        let mut previous = match previous {
+            Some(Ok(previous)) => Some(previous),
+            _ => None,
+        };
+        for dependency in &previous_trace {
+            dependency.remove_reader(self.id);
+        }
+        drop(previous_trace);
+
+        // If the evaluation panics, the value is poisoned. Only the dependencies that were read up
+        // to the panic are in the trace, so that the value gets recomputed as soon one of them
+        // changes. The dependencies of the previous run are not restored: The evaluation would
+        // panic again as long as none of the kept ones change.
+        let (new, new_trace) = self.runtime.eval(label.clone(), || compute(&mut previous));
+        let new = new
+            .unwrap_or_else(|payload| Err(Error::Poisoned(PoisonError::new(label, payload))))
+            .map(Arc::new);
+        // Readers are registered before the value is stored, so that changes that happen from now
+        // on invalidate this value.
+        let this: Weak<dyn AnyNode> = self.this.clone();
+        for dependency in &new_trace {
+            dependency.add_reader(self.id, this.clone());
+        }
+
+        let mut state = self.state();
+        let eq = state.eq;
+        let Computed {
+            ref mut value,
+            compute: ref mut compute_slot,
+            ref mut trace,
+            ref mut verified_at,
+            ref mut outdated,
+            ..
+        } = state.primitive
+        else {
+            unreachable!()
+        };
+        let changed = match (eq, &previous, &new) {
+            (Some(eq), Some(previous), Ok(new)) => !eq(previous, new),
+            _ => true,
+        };
+        *compute_slot = Some(compute);
+        *value = Some(new.clone());
+        *trace = new_trace;
+        *verified_at = revision;
+        // Vars that were read might have been changed while computing.
+        *outdated = self.runtime.revision() != revision;
+        if changed {
+            state.changed_at = revision;
+        }
+        drop(state);
+        drop(evaluation);
+        drop(previous);
+        new
+    }
+
+    /// Invalidates the readers after the value changed.
+    fn invalidate_readers(&self) {
+        let mut cause = self
+            .state()
+            .change_location
+            .take()
+            .map(InvalidationCause::new);
+        self.invalidate(cause.as_mut());
+    }
+}
+
+impl<T> AnyNode for Node<T>
+where
+    T: Send + Sync + 'static,
+{
+    fn id(&self) -> NodeId {
+        self.id
+    }
+
+    fn changed(&self, revision: Revision) {
+        self.state().changed_at = revision;
+        self.invalidate_readers();
+    }
+
+    fn invalidate(&self, mut cause: Option<&mut InvalidationCause>) {
+        {
+            let mut state = self.state();
+            if let Computed {
+                ref mut outdated, ..
+            } = state.primitive
+            {
+                if *outdated {
+                    // Readers of an outdated node are already marked as outdated.
+                    return;
+                }
+                *outdated = true;
+                if state.effect {
+                    let this: Weak<dyn AnyNode> = self.this.clone();
+                    self.runtime.schedule_effect(this);
+                }
+            }
+            if let Some(cause) = cause.as_deref_mut() {
+                cause.enter(state.label(self.id));
+                if let Computed { .. } = state.primitive {
+                    #[cfg(feature = "log")]
+                    log::debug!("Invalidated: {cause}");
+                    state.invalidation_cause = Some(cause.clone());
+                }
+            }
+        }
+
+        let readers: Vec<_> = self
+            .readers
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for reader in readers {
+            reader.invalidate(cause.as_deref_mut());
+        }
+        if let Some(cause) = cause {
+            cause.leave();
+        }
+    }
+
+    fn ensure_valid(&self) -> Result<(), Error> {
+        self.evaluate().map(|_| ())
+    }
+
+    fn update(&self) -> Option<Revision> {
+        if self.runtime.detect_cycle(self.id).is_some() {
+            return None;
+        }
+        // Errors are stored like values, readers see them when they read the node.
+        let _ = self.evaluate();
+        Some(self.state().changed_at)
+    }
+
+    fn add_reader(&self, reader: NodeId, node: Weak<dyn AnyNode>) {
+        self.readers.lock().unwrap().insert(reader, node);
+    }
+
+    fn remove_reader(&self, reader: NodeId) {
+        self.readers.lock().unwrap().remove(&reader);
+    }
+
+    fn info(&self) -> NodeInfo {
+        let state = self.state();
+        let (kind, node_state) = state.kind_and_state();
+        let dependencies = match &state.primitive {
+            Var(_) => Vec::new(),
+            Computed { trace, .. } => trace.iter().map(|dependency| dependency.id()).collect(),
+        };
+        let value = match (state.debug, state.primitive.value()) {
+            (Some(debug), Some(value)) => Some(format!("{:?}", DebugWith(value, debug))),
+            _ => None,
+        };
+        NodeInfo {
+            label: state.label(self.id),
+            kind,
+            state: node_state,
+            value,
+            dependencies,
+        }
+    }
+}
+
+impl<T: 'static> Drop for Node<T> {
+    fn drop(&mut self) {
+        self.runtime.unregister(self.id);
+        if let Computed { trace, .. } = &self.state.get_mut().unwrap().primitive {
+            for dependency in trace {
+                dependency.remove_reader(self.id);
+            }
+        }
+    }
+}
+
+/// Marks the end of an evaluation, even if it panics.
+struct Evaluation<'a, T: 'static>(&'a Node<T>);
+
+impl<T: 'static> Drop for Evaluation<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Computed {
+            ref mut evaluating, ..
+        } = state.primitive
+        {
+            *evaluating = None;
+        }
+        drop(state);
+        self.0.evaluated.notify_all();
+    }
+}
+
+struct DebugWith<'a, T>(&'a T, fn(&T, &mut fmt::Formatter<'_>) -> fmt::Result);
+
+impl<T> fmt::Debug for DebugWith<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.1)(self.0, f)
+    }
+}
+
+/// A dependency that is evaluating or verifying forms a cycle. It is considered to be changed, so
+/// that the value is recomputed and the cycle is reported when the dependency is read again.
+fn dependencies_changed(trace: &Trace, verified_at: Revision) -> bool {
+    trace.iter().any(|dependency| {
+        dependency
+            .update()
+            .is_none_or(|changed_at| changed_at > verified_at)
+    })
+}
@@ -1,8 +1,18 @@
-use crate::runtime::{self, Node, NodePtr, RefCellNode, RefCellNodeHandle, Runtime};
+use crate::{
+    graph::NodeKey,
+    runtime::{
+        Dependencies, Frame, Node, NodeId, NodeInfo, NodeKind, NodeLabel, NodeState, RefCellNode,
+        Revision, Runtime, Update, WeakRuntime,
+    },
+    DisposedError, Error, InvalidationCause, PoisonError,
+};
 use std::{
+    any::Any,
     cell::{Ref, RefCell},
-    mem,
-    rc::Rc,
+    fmt, mem,
+    panic::Location,
+    rc::{Rc, Weak},
+    sync::Arc,
 };
 use Primitive::*;
 
@@ -10,144 +20,541 @@ use Primitive::*;
 /// variable that is mutable or a computed value.
 ///
 /// Create instances of this type using the `Runtime::var` and `Runtime::computed` methods.
-pub struct Value<T: 'static>(Rc<RefCell<ValueInner<T>>>);
+pub struct Value<T: 'static> {
+    // The runtime is also stored here, because it must be accessible while the node is evaluating.
+    runtime: Runtime,
+    // The key is also stored here, because the node is borrowed while it is evaluating.
+    key: NodeKey,
+    inner: Rc<RefCell<ValueInner<T>>>,
+}
 
 impl<T> Clone for Value<T> {
     fn clone(&self) -> Self {
-        Value(self.0.clone())
+        Value {
+            runtime: self.runtime.clone(),
+            key: self.key,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// A handle to a value that does not keep it alive. Create it with `Value::downgrade()`.
+pub struct WeakValue<T: 'static> {
+    runtime: WeakRuntime,
+    key: NodeKey,
+    inner: Weak<RefCell<ValueInner<T>>>,
+}
+
+impl<T> Clone for WeakValue<T> {
+    fn clone(&self) -> Self {
+        WeakValue {
+            runtime: self.runtime.clone(),
+            key: self.key,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> WeakValue<T> {
+    /// Returns the value if it is still alive.
+    pub fn upgrade(&self) -> Option<Value<T>> {
+        let inner = self.inner.upgrade()?;
+        Some(Value {
+            // Values keep their runtime alive.
+            runtime: self.runtime.upgrade().unwrap(),
+            key: self.key,
+            inner,
+        })
+    }
+
+    /// Returns `true` if the value is still alive.
+    pub fn is_alive(&self) -> bool {
+        self.inner.strong_count() > 0
     }
 }
 
 impl<T> Value<T> {
     pub(crate) fn new_var(runtime: &Runtime, value: T) -> Self {
-        let inner = ValueInner {
-            runtime: runtime.clone(),
-            readers: Default::default(),
-            primitive: Var(value),
-        };
-        Value(Rc::new(RefCell::new(inner)))
+        Value::new(runtime, NodeKind::Var, Var(value))
     }
 
-    pub(crate) fn new_computed(runtime: &Runtime, compute: impl FnMut() -> T + 'static) -> Self {
-        let inner = ValueInner {
-            runtime: runtime.clone(),
-            readers: Default::default(),
-            primitive: Computed {
-                value: None,
-                compute: Box::new(compute),
-                trace: Vec::new(),
-            },
+    pub(crate) fn new_computed(
+        runtime: &Runtime,
+        mut compute: impl FnMut() -> T + 'static,
+    ) -> Self {
+        Value::new_try_computed(runtime, move || Ok(compute()))
+    }
+
+    pub(crate) fn new_try_computed(
+        runtime: &Runtime,
+        mut compute: impl FnMut() -> Result<T, Error> + 'static,
+    ) -> Self {
+        let compute = Box::new(move |_: &mut Option<T>| compute());
+        Value::new(runtime, NodeKind::Computed, Primitive::computed(compute))
+    }
+
+    pub(crate) fn new_computed_with_prev(
+        runtime: &Runtime,
+        mut compute: impl FnMut(Option<T>) -> T + 'static,
+    ) -> Self {
+        let compute = Box::new(move |previous: &mut Option<T>| Ok(compute(previous.take())));
+        Value::new(runtime, NodeKind::Computed, Primitive::computed(compute))
+    }
+
+    /// Creates a computed value that schedules itself in the runtime when it gets outdated.
+    pub(crate) fn new_effect(runtime: &Runtime, mut run: impl FnMut() -> T + 'static) -> Self {
+        let run = Box::new(move |_: &mut Option<T>| Ok(run()));
+        Value::new(runtime, NodeKind::Effect, Primitive::computed(run))
+    }
+
+    fn new(runtime: &Runtime, kind: NodeKind, primitive: Primitive<T>) -> Self {
+        let changed_at = match primitive {
+            Var(_) => runtime.revision(),
+            Computed { .. } => 0,
         };
+        let inner = Rc::new_cyclic(|this: &Weak<RefCell<ValueInner<T>>>| {
+            let key = runtime.insert_node(kind, this.clone());
+            RefCell::new(ValueInner {
+                key,
+                runtime: runtime.clone(),
+                changed_at,
+                eq: None,
+                debug: None,
+                cycle_fallback: None,
+                change_location: None,
+                write_back: None,
+                disposed: false,
+                primitive,
+            })
+        });
+        let key = inner.borrow().key;
+        runtime.adopt(inner.clone());
+        Value {
+            runtime: runtime.clone(),
+            key,
+            inner,
+        }
+    }
 
-        Value(Rc::new(RefCell::new(inner)))
+    /// Compare new values to the current one using `PartialEq` and don't propagate changes if
+    /// they are equal.
+    pub(crate) fn with_eq(self) -> Self
+    where
+        T: PartialEq,
+    {
+        self.inner.borrow_mut().eq = Some(T::eq);
+        self
+    }
+
+    /// Make the value's `Debug` output available to diagnostics, like `Runtime::export_dot()`.
+    pub fn with_debug(self) -> Self
+    where
+        T: fmt::Debug,
+    {
+        self.inner.borrow_mut().debug = Some(T::fmt);
+        self
+    }
+
+    /// Return `fallback` to reads of this value that happen while the value is evaluating.
+    pub(crate) fn with_cycle_fallback(self, fallback: T) -> Self {
+        self.inner.borrow_mut().cycle_fallback = Some(Rc::new(fallback));
+        self
+    }
+
+    /// Sets the name that is shown in diagnostics.
+    pub fn named(self, name: impl Into<Arc<str>>) -> Self {
+        self.runtime.graph_mut().node_mut(self.key).label.name = Some(name.into());
+        self
+    }
+
+    /// The id of the node in the runtime.
+    pub fn id(&self) -> NodeId {
+        self.label().id
+    }
+
+    /// The name set with `named()`.
+    pub fn name(&self) -> Option<Arc<str>> {
+        self.label().name
+    }
+
+    fn label(&self) -> NodeLabel {
+        self.runtime.graph().node(self.key).label.clone()
     }
 
     /// If needed, evaluates the value, then clones it and returns it. Requires the contained value to implement
     /// `Clone`.
+    ///
+    /// Panics if a cycle is detected or the evaluation failed. See `try_get()`.
     pub fn get(&self) -> T
     where
         T: Clone,
     {
-        self.get_ref().clone()
+        self.try_get().unwrap_or_else(|e| panic!("{e}"))
     }
 
     /// Evaluates the value and returns a reference to the contained value.
-    pub fn get_ref(&self) -> Ref<T> {
-        self.ensure_valid_and_track_read();
-        let r = self.0.borrow();
-        Ref::map(r, |r| r.primitive.value().unwrap())
+    ///
+    /// Panics if a cycle is detected or the evaluation failed. See `try_get_ref()`.
+    pub fn get_ref(&self) -> Ref<'_, T> {
+        self.try_get_ref().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like `get()`, but the read is not tracked as a dependency of the value that is currently
+    /// evaluating. See `Runtime::untrack()`.
+    pub fn get_untracked(&self) -> T
+    where
+        T: Clone,
+    {
+        self.runtime.untrack(|| self.get())
+    }
+
+    /// Like `get_ref()`, but the read is not tracked as a dependency of the value that is
+    /// currently evaluating. See `Runtime::untrack()`.
+    pub fn peek(&self) -> Ref<'_, T> {
+        self.runtime.untrack(|| self.get_ref())
+    }
+
+    /// Like `get()`, but returns an error if the value is read while it is evaluating, if its
+    /// evaluation panicked, or if it is a fallible computed value that failed.
+    ///
+    /// If the value was created with `Runtime::computed_with_cycle_fallback`, the fallback value is
+    /// returned instead of a cycle error. Reads of the fallback value are not tracked.
+    ///
+    /// A value whose evaluation panicked is poisoned and returns the same error until one of the
+    /// dependencies it read before the panic changes.
+    pub fn try_get(&self) -> Result<T, Error>
+    where
+        T: Clone,
+    {
+        match self.try_get_ref() {
+            Ok(value) => Ok(value.clone()),
+            Err(Error::Cycle(error)) => match self.runtime.cycle_fallback(self.key) {
+                Some(fallback) => Ok(fallback.downcast_ref::<T>().unwrap().clone()),
+                None => Err(Error::Cycle(error)),
+            },
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Like `get_ref()`, but returns an error if the value is read while it is evaluating, if its
+    /// evaluation panicked, or if it is a fallible computed value that failed.
+    pub fn try_get_ref(&self) -> Result<Ref<'_, T>, Error> {
+        self.ensure_valid_and_track_read()?;
+        let r = self.inner.borrow();
+        Ok(Ref::map(r, |r| r.primitive.value().unwrap()))
     }
 
     /// Track the value for receiving change notifications when it changes.
     pub fn track(&self) {
-        self.ensure_valid_and_track_read();
+        self.ensure_valid_and_track_read()
+            .unwrap_or_else(|e| panic!("{e}"));
     }
 
     /// Makes sure the value is evaluated then takes it out and invalidates it.
     ///
     /// This can't be called inside a evaluation context.
+    #[track_caller]
     pub fn take(&mut self) -> T {
-        let mut inner = self.0.borrow_mut();
+        let mut inner = self.inner.borrow_mut();
         debug_assert!(inner.runtime.current().is_none());
+        inner.record_change_location(Location::caller());
         inner.take()
     }
 
+    /// Sets the value of a var.
+    ///
+    /// If the var was created with `Runtime::var_eq` and `value` is equal to the current value,
+    /// the var stays unchanged and its readers are not invalidated.
+    #[track_caller]
     pub fn set(&mut self, value: T) {
+        self.update_lens();
+        if self.inner.borrow().is_equal_to(&value) {
+            return;
+        }
         self.apply(|_| value);
     }
 
+    /// Sets the value of a var, but only if it differs from the current value.
+    #[track_caller]
+    pub fn set_if_changed(&mut self, value: T)
+    where
+        T: PartialEq,
+    {
+        self.update_lens();
+        if self.inner.borrow().primitive.value() == Some(&value) {
+            return;
+        }
+        self.apply(|_| value);
+    }
+
+    /// Changes the value of a var by applying `f` to it and invalidates all its readers.
+    ///
+    /// Inside a batch, the invalidation is postponed until the batch commits.
+    ///
+    /// Changes to a lens are written back into its source, see `lens()`.
+    #[track_caller]
     pub fn apply(&mut self, f: impl FnOnce(T) -> T) {
-        self.0.borrow_mut().apply(f);
+        self.apply_at(Location::caller(), f)
+    }
+
+    fn apply_at(&self, location: &'static Location<'static>, f: impl FnOnce(T) -> T) {
+        let mut inner = self.inner.borrow_mut();
+        if let Some(write_back) = inner.write_back.clone() {
+            let value = inner.take_value();
+            drop(inner);
+            write_back(f(value), location);
+            return;
+        }
+        inner.record_change_location(location);
+        if self.runtime.is_batching() {
+            inner.change(f);
+            self.runtime
+                .defer_invalidation(self.key, self.inner.clone());
+        } else {
+            inner.apply(f);
+        }
+    }
+
+    /// Creates a value that focuses on a part of this value.
+    ///
+    /// The lens reads like a computed value that depends on this value. Setting the lens writes
+    /// the new part back into this value with `set`, which invalidates all readers of this value.
+    /// This value must be a var or another lens.
+    pub fn lens<U>(
+        &self,
+        get: impl Fn(&T) -> &U + 'static,
+        set: impl Fn(&mut T, U) + 'static,
+    ) -> Value<U>
+    where
+        U: Clone,
+    {
+        let lens = {
+            let source = self.clone();
+            self.runtime
+                .computed(move || get(&source.get_ref()).clone())
+        };
+        let source = self.clone();
+        lens.inner.borrow_mut().write_back = Some(Rc::new(move |part, location| {
+            source.apply_at(location, |mut value| {
+                set(&mut value, part);
+                value
+            })
+        }));
+        lens
+    }
+
+    /// Creates a projection of a field of this value.
+    ///
+    /// A projection is a lens that compares the field to its previous value. When this value
+    /// changes, only readers of projections whose field changed are recomputed. Readers of other
+    /// fields are verified, but not recomputed.
+    pub fn project<U>(
+        &self,
+        get: impl Fn(&T) -> &U + 'static,
+        set: impl Fn(&mut T, U) + 'static,
+    ) -> Value<U>
+    where
+        U: Clone + PartialEq,
+    {
+        self.lens(get, set).with_eq()
+    }
+
+    /// Creates a handle that does not keep this value alive.
+    ///
+    /// Capture weak values in compute functions to refer to values without creating reference
+    /// cycles.
+    pub fn downgrade(&self) -> WeakValue<T> {
+        WeakValue {
+            runtime: self.runtime.downgrade(),
+            key: self.key,
+            inner: Rc::downgrade(&self.inner),
+        }
+    }
+
+    /// Brings a lens up to date, so that new values are compared against the current value of
+    /// its source.
+    fn update_lens(&self) {
+        if self.inner.borrow().write_back.is_some() {
+            let _ = self.update();
+        }
+    }
+
+    /// The cause of the last invalidation of this value, if invalidation tracing is enabled with
+    /// `Runtime::trace_invalidations()`.
+    ///
+    /// Only the first change that reaches a value after it was evaluated is recorded, later
+    /// changes stop at the value because it is already outdated.
+    pub fn last_invalidation_cause(&self) -> Option<InvalidationCause> {
+        let graph = self.runtime.graph();
+        graph.node(self.key).invalidation_cause.clone()
     }
 
     pub fn runtime(&self) -> Runtime {
-        self.0.borrow().runtime.clone()
+        self.runtime.clone()
+    }
+
+    /// Brings the value up to date without tracking the read.
+    pub(crate) fn update(&self) -> Result<(), Error> {
+        self.inner.borrow_mut().ensure_valid()
     }
 
-    fn ensure_valid_and_track_read(&self) {
-        let inner = self.0.try_borrow_mut();
+    fn ensure_valid_and_track_read(&self) -> Result<(), Error> {
+        let inner = self.inner.try_borrow_mut();
         let Ok(mut inner) = inner else {
             // `inner` is already borrowed, this means that there are another `get_ref()` is active,
-            // or there is a cycle in the evaluation. The former is fine if the value is valid.
-            let inner = self.0.borrow();
-            #[cfg(debug_assertions)]
-            debug_assert!(inner.is_valid());
-            self.track_read(&inner);
-            return;
+            // or there is a cycle in the evaluation. In the former case, the current value is
+            // returned. If it is outdated, the reader stays outdated, so that it is verified again
+            // after the reference is dropped.
+            if let Some(cycle) = self.runtime.detect_cycle(self.key) {
+                return Err(Error::Cycle(cycle));
+            }
+            if !self.inner.borrow().is_valid() {
+                self.runtime.track_stale_read();
+            }
+            self.runtime.track_read(self.key, self.inner.clone());
+            return Ok(());
         };
-        inner.ensure_valid();
-        self.track_read(&inner);
+        // The read is tracked first, so that a reader depends on this value even if its
+        // evaluation fails.
+        self.runtime.track_read(self.key, self.inner.clone());
+        inner.ensure_valid()
     }
 
-    fn track_read(&self, inner: &ValueInner<T>) {
-        let reader = inner.runtime.current();
-        if let Some(mut reader) = reader {
-            inner.readers.borrow_mut().insert(reader);
+    /// The node of the value. Keeps the value alive, but not its runtime.
+    pub(crate) fn node(&self) -> Rc<dyn RefCellNode> {
+        self.inner.clone()
+    }
 
-            let reader = unsafe { reader.as_mut() };
-            reader.track_read_from(self.0.clone());
-        }
+    /// Returns `true` if other values read this value. Values that are evaluating are considered
+    /// to be read.
+    pub(crate) fn is_read(&self) -> bool {
+        self.inner.try_borrow().is_err() || !self.runtime.graph().node(self.key).readers.is_empty()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn key(&self) -> NodeKey {
+        self.key
     }
 
     #[cfg(test)]
     pub fn is_valid(&self) -> bool {
-        self.0.borrow().primitive.value().is_some()
+        self.inner.borrow().is_valid()
     }
 
     #[cfg(test)]
     pub(crate) fn readers_count(&self) -> usize {
-        self.0.borrow().readers.borrow().len()
+        self.runtime.graph().node(self.key).readers.len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn trace_len(&self) -> usize {
+        self.runtime.graph().node(self.key).trace.len()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Value<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Values that are evaluating can't be inspected.
+        let Ok(inner) = self.inner.try_borrow() else {
+            let label = self.label();
+            return f
+                .debug_struct("Value")
+                .field("id", &label.id)
+                .field("name", &label.name)
+                .finish_non_exhaustive();
+        };
+        let info = inner.info();
+        let mut debug = f.debug_struct("Value");
+        debug
+            .field("id", &info.label.id)
+            .field("name", &info.label.name)
+            .field("kind", &format_args!("{}", info.kind))
+            .field("state", &format_args!("{}", info.state));
+        match &inner.primitive {
+            Var(value)
+            | Computed {
+                value: Some(Ok(value)),
+                ..
+            } => debug.field("value", value),
+            Computed {
+                value: Some(Err(error)),
+                ..
+            } => debug.field("error", error),
+            Computed { value: None, .. } => &mut debug,
+        };
+        debug.finish()
+    }
+}
+
+impl<T> fmt::Display for Value<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.inner.try_borrow() {
+            Ok(inner) => {
+                let info = inner.info();
+                write!(f, "{}: {}, {}", info.label, info.kind, info.state)
+            }
+            Err(_) => write!(f, "{}: evaluating", self.label()),
+        }
     }
 }
 
 struct ValueInner<T: 'static> {
+    key: NodeKey,
     runtime: Runtime,
-    // The nodes that read from this node. Nodes reading from this node are responsible for removing
-    // themselves from us in their drop implementation.
-    //
-    // Note that readers _must_ be stored in a `RefCell`, because there might be existing references
-    // (retrieved via `get_ref()`) already existing at the time new readers are added. See #8.
-    readers: RefCell<runtime::Readers>,
+    // The revision in which the value changed the last time. Readers that were verified before that
+    // revision are outdated.
+    changed_at: Revision,
+    // If set, a new value that is equal to the previous one is not considered a change.
+    eq: Option<fn(&T, &T) -> bool>,
+    // Formats the value for diagnostics.
+    debug: Option<fn(&T, &mut fmt::Formatter<'_>) -> fmt::Result>,
+    // Returned to reads of this value that happen while it is evaluating.
+    cycle_fallback: Option<Rc<dyn Any>>,
+    /// Where the value was changed, if invalidation tracing is enabled. Taken when the readers
+    /// are invalidated.
+    change_location: Option<&'static Location<'static>>,
+    // Set for lenses. Writes a changed value back into the source of the lens.
+    write_back: Option<WriteBack<T>>,
+    // Set when the scope of the value was disposed. A disposed computed value is never evaluated
+    // again.
+    disposed: bool,
     primitive: Primitive<T>,
 }
 
+type WriteBack<T> = Rc<dyn Fn(T, &'static Location<'static>)>;
+
 enum Primitive<T> {
     Var(T),
     Computed {
-        value: Option<T>,
-        compute: Box<dyn FnMut() -> T>,
-        // Nodes that this node read from in the previous evaluation.
-        // Might contain duplicates and locks them in memory via `Rc`.
-        // Cleared on invalidation.
-        trace: runtime::Trace,
+        // The result of the last evaluation. An error if the evaluation failed or panicked.
+        value: Option<Result<T, Error>>,
+        compute: Compute<T>,
+        // The nodes that this node read from in the previous evaluation. Replaced when the value
+        // is recomputed.
+        dependencies: Dependencies,
+        // The revision in which the value was computed or verified the last time.
+        verified_at: Revision,
     },
 }
 
+/// Computes the value of a computed. Receives the previous value, which is still set after the
+/// call if `compute` did not take it.
+type Compute<T> = Box<dyn FnMut(&mut Option<T>) -> Result<T, Error>>;
+
 impl<T> Primitive<T> {
+    fn computed(compute: Compute<T>) -> Self {
+        Computed {
+            value: None,
+            compute,
+            dependencies: Vec::new(),
+            verified_at: 0,
+        }
+    }
+
     fn value(&self) -> Option<&T> {
         match self {
             Var(value) => Some(value),
-            Computed { value, .. } => value.as_ref(),
+            Computed { value, .. } => value.as_ref()?.as_ref().ok(),
         }
     }
 
@@ -163,148 +570,270 @@ impl<T> Primitive<T> {
 
 impl<T> ValueInner<T> {
     fn apply(&mut self, f: impl FnOnce(T) -> T) {
-        // TODO: only relevant in the Var path
-        self.invalidate();
+        self.change(f);
+        let revision = self.runtime.new_revision();
+        self.changed(revision);
+    }
+
+    /// Changes the value without invalidating the readers.
+    fn change(&mut self, f: impl FnOnce(T) -> T) {
         self.primitive.apply(f);
     }
 
+    /// Returns `true` if this value compares for equality and is equal to `value`.
+    fn is_equal_to(&self, value: &T) -> bool {
+        match (self.eq, self.primitive.value()) {
+            (Some(eq), Some(current)) => eq(current, value),
+            _ => false,
+        }
+    }
+
     pub fn take(&mut self) -> T {
-        self.ensure_valid();
+        // TODO: Consider returning the value from invalidate().
+        let value = self.take_value();
+        // Readers that verify in the same revision would not see that the recomputed value
+        // changed.
+        self.runtime.new_revision();
+        self.invalidate_readers();
+        value
+    }
+
+    /// Makes sure the value is evaluated, then takes it out without invalidating the readers.
+    fn take_value(&mut self) -> T {
+        if let Err(e) = self.ensure_valid() {
+            panic!("{e}");
+        }
         match self.primitive {
             Var(_) => panic!("Cannot take a var"),
-            Computed { ref mut value, .. } => {
-                // TODO: Consider returning the value from invalidate().
-                let value = value.take().unwrap();
-                self.invalidate();
-                value
-            }
+            Computed { ref mut value, .. } => value.take().unwrap().unwrap(),
         }
     }
 
-    pub fn ensure_valid(&mut self) {
-        // TODO: `self_ptr` is only used in the `Computed` path.
-        let self_ptr = self.as_ptr();
+    /// Brings the value up to date and returns the error if the evaluation panicked.
+    pub fn ensure_valid(&mut self) -> Result<(), Error> {
         match self.primitive {
             Var(_) => {
                 // Always valid
+                Ok(())
             }
             Computed {
                 ref mut value,
                 ref mut compute,
-                ..
+                ref mut dependencies,
+                ref mut verified_at,
             } => {
-                if value.is_none() {
-                    // Readers must be empty when recomputing.
-                    debug_assert!(self.readers.borrow().is_empty());
-                    self.runtime.eval(self_ptr, || {
-                        *value = Some(compute());
-                    });
+                if self.disposed {
+                    return match value {
+                        Some(result) => status(result),
+                        None => {
+                            let label = self.runtime.graph().node(self.key).label.clone();
+                            Err(Error::Disposed(DisposedError::new(label)))
+                        }
+                    };
+                }
+                if let Some(result) = value {
+                    if !self.runtime.graph().node(self.key).outdated {
+                        return status(result);
+                    }
+                    let frame = Frame::new(self.key, self.cycle_fallback.clone());
+                    let changed = self
+                        .runtime
+                        .verify(frame, || dependencies_changed(dependencies, *verified_at));
+                    match changed {
+                        Some(false) => {
+                            *verified_at = self.runtime.revision();
+                            self.runtime.graph_mut().node_mut(self.key).outdated = false;
+                            return status(result);
+                        }
+                        // A dependency can't be updated while its value is borrowed. The current
+                        // value is returned, but stays outdated until it can be verified.
+                        None => return status(result),
+                        Some(true) => {}
+                    }
+                }
+
+                // The previous dependencies are kept alive until the value is recomputed, so that
+                // dependencies that are only referenced by them, like cached query results,
+                // survive if they are read again.
+                self.runtime.graph_mut().clear_trace(self.key);
+                let previous_dependencies = mem::take(dependencies);
+                let mut previous = match value.take() {
+                    Some(Ok(previous)) => Some(previous),
+                    _ => None,
+                };
+                let revision = self.runtime.revision();
+                let frame = Frame::new(self.key, self.cycle_fallback.clone());
+                // If the evaluation panics, the value is poisoned. Only the dependencies that were
+                // read up to the panic are kept, so that the value gets recomputed as soon one of
+                // them changes. The dependencies of the previous run are not restored: The
+                // evaluation would panic again as long as none of the kept ones change.
+                let (new, frame) = self.runtime.eval(frame, || compute(&mut previous));
+                *dependencies = frame.dependencies;
+                let new = new.unwrap_or_else(|payload| {
+                    let label = self.runtime.graph().node(self.key).label.clone();
+                    Err(Error::Poisoned(PoisonError::new(label, payload)))
+                });
+                let changed = match (self.eq, &previous, &new) {
+                    (Some(eq), Some(previous), Ok(new)) => !eq(previous, new),
+                    _ => true,
+                };
+                if changed {
+                    self.changed_at = revision;
+                }
+                let result = status(&new);
+                *value = Some(new);
+                // A value that read a borrowed outdated value is not verified at this revision, so
+                // that the update of the outdated value is detected as a change.
+                if !frame.stale {
+                    *verified_at = revision;
                 }
+                // Vars that were read might have been changed while computing.
+                self.runtime.graph_mut().node_mut(self.key).outdated =
+                    frame.stale || self.runtime.revision() != revision;
+                drop(previous_dependencies);
+                result
             }
         }
     }
 
-    #[cfg(debug_assertions)]
-    fn is_valid(&self) -> bool {
-        match self.primitive {
-            Var(_) => true,
-            Computed { ref value, .. } => value.is_some(),
+    /// Remembers `location` as the cause of the next invalidation.
+    fn record_change_location(&mut self, location: &'static Location<'static>) {
+        if self.runtime.traces_invalidations() {
+            self.change_location = Some(location);
         }
     }
 
-    fn as_ptr(&self) -> NodePtr {
-        NodePtr::new(self)
+    /// Invalidates the readers after the value changed.
+    fn invalidate_readers(&mut self) {
+        let mut cause = self.change_location.take().map(InvalidationCause::new);
+        self.runtime.invalidate(self.key, cause.as_mut());
+    }
+
+    /// Drops the trace of a computed value and returns its dependencies, so that they can be
+    /// dropped after the value is released.
+    fn drop_trace(&mut self) -> Dependencies {
+        match self.primitive {
+            Var(_) => Vec::new(),
+            Computed {
+                ref mut dependencies,
+                ..
+            } => {
+                let mut graph = self.runtime.graph_mut();
+                graph.clear_trace(self.key);
+                graph.node_mut(self.key).outdated = false;
+                mem::take(dependencies)
+            }
+        }
     }
 }
 
 impl<T> Node for ValueInner<T> {
-    fn invalidate(&mut self) {
-        // We first invalidate all readers so that their values are dropped in reverse order. They
-        // may indirectly depend on this value here to be alive and if not, it's nice to see that
-        // dependencies are dropped first.
-        //
-        // TODO: Validate this behavior by creating a test case that checks the drop order.
-        {
-            // TODO: Can't borrow readers here while propagating the invalidation, because we might
-            // be called from a reader that wants to remove itself.
-            //
-            // This might be simplified by using an invalidation context that guarantees that
-            // readers are only removed once.
-            let mut readers = mem::take(&mut *self.readers.borrow_mut());
-            for reader in &readers {
-                unsafe { reader.clone().as_mut() }.invalidate();
-            }
-            // Clear the readers
-            readers.clear();
-
-            // Put the empty ones back to keep the capacity
-            let self_readers = &mut *self.readers.borrow_mut();
-            // Readers in this instance not allowed to change while invalidation runs.
-            debug_assert!(self_readers.is_empty());
-            *self_readers = readers;
-        };
+    fn changed(&mut self, revision: Revision) {
+        self.changed_at = revision;
+        self.invalidate_readers();
+    }
 
-        // Clean up this value last
-        {
-            // TODO: `self_ptr` is only used in the `Computed` path.
-            let self_ptr = self.as_ptr();
-            match self.primitive {
-                Var(_) => {}
-                Computed {
-                    ref mut value,
-                    ref mut trace,
-                    ..
-                } => {
-                    *value = None;
-                    // Drop the trace and remove us from all dependencies Because we may already be
-                    // called from a dependency, we can't use `borrow_mut` here.
-                    //
-                    // This is most likely unsound, because we access two `&mut` references to the same
-                    // trait object.
-                    drop_trace(self_ptr, trace)
-                }
-            }
-        }
+    fn update(&mut self) -> Result<(), Error> {
+        self.ensure_valid()
+    }
+
+    fn changed_at(&self) -> Revision {
+        self.changed_at
     }
 
-    fn track_read_from(&mut self, from: Rc<dyn RefCellNode>) {
+    fn is_valid(&self) -> bool {
         match self.primitive {
-            Var(_) => {
-                panic!("A var does not support tracing dependencies");
+            Var(_) => true,
+            Computed { ref value, .. } => {
+                value.is_some() && !self.runtime.graph().node(self.key).outdated
             }
-            Computed { ref mut trace, .. } => trace.push(RefCellNodeHandle(from)),
         }
     }
 
-    fn remove_reader(&mut self, reader: NodePtr) {
-        // TODO: Now that `borrow_mut()` is used here, remove_reader() can use `&self`.
-        self.readers.borrow_mut().remove(&reader);
+    fn dispose(&mut self) -> Dependencies {
+        self.disposed = true;
+        self.drop_trace()
+    }
+
+    fn reset(&mut self) -> Dependencies {
+        let dependencies = self.drop_trace();
+        if let Computed { ref mut value, .. } = self.primitive {
+            *value = None;
+        }
+        dependencies
+    }
+
+    fn info(&self) -> NodeInfo {
+        let graph = self.runtime.graph();
+        let node = graph.node(self.key);
+        let state = match self.primitive {
+            Var(_) => NodeState::Valid,
+            Computed { ref value, .. } => match value {
+                None => NodeState::Unevaluated,
+                Some(Err(_)) => NodeState::Failed,
+                Some(Ok(_)) if node.outdated => NodeState::Outdated,
+                Some(Ok(_)) => NodeState::Valid,
+            },
+        };
+        let dependencies = node
+            .trace
+            .iter()
+            .filter_map(|&dependency| Some(graph.get(dependency)?.label.id))
+            .collect();
+        let value = match (self.debug, self.primitive.value()) {
+            (Some(debug), Some(value)) => Some(format!("{:?}", DebugWith(value, debug))),
+            _ => None,
+        };
+        NodeInfo {
+            label: node.label.clone(),
+            kind: node.kind,
+            state,
+            value,
+            dependencies,
+        }
     }
 }
 
-impl<T> Drop for ValueInner<T> {
-    fn drop(&mut self) {
-        debug_assert!(self.readers.borrow().is_empty());
+/// Formats a value with a `Debug` implementation that was captured as a function pointer.
+struct DebugWith<'a, T>(&'a T, fn(&T, &mut fmt::Formatter<'_>) -> fmt::Result);
 
-        // TODO: `self_ptr` is only used in the `Computed` path.
-        let self_ptr = self.as_ptr();
+impl<T> fmt::Debug for DebugWith<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.1)(self.0, f)
+    }
+}
 
-        match self.primitive {
-            Var(_) => {}
-            Computed { ref mut trace, .. } => {
-                drop_trace(self_ptr, trace);
-            }
-        }
+impl<T> Drop for ValueInner<T> {
+    fn drop(&mut self) {
+        // The dependencies are dropped after the node was removed from their readers.
+        self.runtime.remove_node(self.key);
     }
 }
 
-/// Removes the trace and removes this node from all dependencies.
-fn drop_trace(self_ptr: NodePtr, trace: &mut runtime::Trace) {
-    for dependency in trace.iter() {
-        unsafe { dependency.as_mut().remove_reader(self_ptr) };
+fn status<T>(result: &Result<T, Error>) -> Result<(), Error> {
+    result.as_ref().map(|_| ()).map_err(Clone::clone)
+}
+
+/// Brings the dependencies up to date in the order they were read and returns `true` if one of
+/// them changed after `verified_at`.
+///
+/// The first changed dependency stops the verification, because the ones read later might not be
+/// read anymore when the value is recomputed.
+///
+/// A dependency that is evaluating or verifying forms a cycle. It is considered to be changed, so
+/// that the value is recomputed and the cycle is reported when the dependency is read again.
+///
+/// Returns `None` if an outdated dependency can't be updated because its value is borrowed. Whether
+/// it changed is unknown until the reference is dropped.
+fn dependencies_changed(dependencies: &Dependencies, verified_at: Revision) -> Option<bool> {
+    for dependency in dependencies {
+        match dependency.update() {
+            Update::Current(changed_at) if changed_at <= verified_at => {}
+            Update::Current(_) | Update::Cycle => return Some(true),
+            Update::Borrowed => return None,
+        }
     }
-    // TODO: when called from drop(), this is redundant.
-    trace.clear();
+    Some(false)
 }
 
 #[cfg(test)]
@@ -320,4 +849,15 @@ mod tests {
         #[allow(clippy::redundant_clone)]
         let _ = value.clone();
     }
+
+    #[test]
+    fn weak_values_do_not_keep_values_alive() {
+        let runtime = Runtime::new();
+        let value = runtime.var(1);
+        let weak = value.downgrade();
+        assert_eq!(weak.upgrade().unwrap().get(), 1);
+        drop(value);
+        assert!(!weak.is_alive());
+        assert!(weak.upgrade().is_none());
+    }
 }